@@ -0,0 +1,313 @@
+use crate::config::{BreakSettings, ShiftSettings, ShiftsSettings};
+use crate::utils::parse_time;
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use eyre::{bail, eyre, Result};
+use serde::Deserialize;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+#[derive(Debug, Clone)]
+pub struct Break {
+    pub start: NaiveTime,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Shift {
+    pub name: String,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub breaks: Vec<Break>,
+}
+
+/// Перерыв, привязанный к конкретной дате.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakWindow {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+}
+
+/// Календарь смен и перерывов. Создается из секции `[shifts]` с проверкой
+/// пересечений, поэтому хранит смены и перерывы в хронологическом порядке.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "ShiftsSettings")]
+pub struct ShiftCalendar {
+    shifts: Vec<Shift>,
+}
+
+impl Shift {
+    fn length(&self) -> Duration {
+        let minutes = minutes_between(self.start, self.end);
+        Duration::minutes(if minutes == 0 {
+            MINUTES_PER_DAY
+        } else {
+            minutes
+        })
+    }
+
+    fn offset_of(&self, time: NaiveTime) -> Duration {
+        Duration::minutes(minutes_between(self.start, time))
+    }
+
+    fn starts_on(&self, day: NaiveDate) -> NaiveDateTime {
+        day.and_time(self.start)
+    }
+}
+
+impl ShiftCalendar {
+    pub fn new(mut shifts: Vec<Shift>) -> Result<Self> {
+        if shifts.is_empty() {
+            bail!("Не задано ни одной смены");
+        }
+        shifts.sort_by_key(|s| s.start);
+
+        for shift in shifts.iter_mut() {
+            let start = shift.start;
+            shift
+                .breaks
+                .sort_by_key(|b| minutes_between(start, b.start));
+            Self::validate_shift(shift)?;
+        }
+
+        for (i, a) in shifts.iter().enumerate() {
+            for b in shifts.iter().skip(i + 1) {
+                if a.name == b.name {
+                    bail!("Смена \"{}\" описана несколько раз", a.name);
+                }
+                if shifts_overlap(a, b) {
+                    bail!(
+                        "Смены \"{}\" ({}-{}) и \"{}\" ({}-{}) пересекаются",
+                        a.name,
+                        a.start.format("%H:%M"),
+                        a.end.format("%H:%M"),
+                        b.name,
+                        b.start.format("%H:%M"),
+                        b.end.format("%H:%M")
+                    );
+                }
+            }
+        }
+
+        Ok(Self { shifts })
+    }
+
+    fn validate_shift(shift: &Shift) -> Result<()> {
+        let mut prev: Option<&Break> = None;
+        for br in &shift.breaks {
+            if br.duration <= Duration::zero() {
+                bail!(
+                    "Смена \"{}\": перерыв в {} должен иметь положительную длительность",
+                    shift.name,
+                    br.start.format("%H:%M")
+                );
+            }
+            if shift.offset_of(br.start) + br.duration > shift.length() {
+                bail!(
+                    "Смена \"{}\": перерыв в {} выходит за границы смены {}-{}",
+                    shift.name,
+                    br.start.format("%H:%M"),
+                    shift.start.format("%H:%M"),
+                    shift.end.format("%H:%M")
+                );
+            }
+            if let Some(prev) = prev {
+                if shift.offset_of(prev.start) + prev.duration > shift.offset_of(br.start) {
+                    bail!(
+                        "Смена \"{}\": перерывы в {} и {} пересекаются",
+                        shift.name,
+                        prev.start.format("%H:%M"),
+                        br.start.format("%H:%M")
+                    );
+                }
+            }
+            prev = Some(br);
+        }
+        Ok(())
+    }
+
+    pub fn shifts(&self) -> &[Shift] {
+        &self.shifts
+    }
+
+    /// Смена, в которую попадает указанный момент времени.
+    pub fn shift_at(&self, at: NaiveDateTime) -> Option<&Shift> {
+        let date = at.date();
+        [date.pred_opt(), Some(date)]
+            .into_iter()
+            .flatten()
+            .flat_map(|day| self.shifts.iter().map(move |s| (day, s)))
+            .find(|(day, s)| {
+                let start = s.starts_on(*day);
+                start <= at && at < start + s.length()
+            })
+            .map(|(_, s)| s)
+    }
+
+    /// Суммарная длительность перерывов между `start` и `end`.
+    ///
+    /// При `calc_on_end = true` перерыв учитывается, если его начало попадает в интервал.
+    /// При `calc_on_end = false` достаточно захватить хотя бы минуту перерыва,
+    /// а конец интервала сдвигается на длительность каждого учтенного перерыва.
+    pub fn breaks_between(
+        &self,
+        start: NaiveDateTime,
+        end: NaiveDateTime,
+        calc_on_end: bool,
+    ) -> Duration {
+        let mut breaks = Duration::zero();
+        if end <= start {
+            return breaks;
+        }
+        let max_duration = match self.max_break_duration() {
+            Some(d) => d,
+            None => return breaks,
+        };
+
+        let mut end = end;
+        for window in self.occurrences(start) {
+            if window.start > end + max_duration {
+                break;
+            }
+            let duration = window.end - window.start;
+            let threshold = if calc_on_end {
+                window.start
+            } else {
+                window.start - duration + Duration::minutes(1)
+            };
+            if threshold > start && threshold <= end {
+                breaks += duration;
+                if !calc_on_end {
+                    end += duration;
+                }
+            }
+        }
+        breaks
+    }
+
+    /// Перерывы, которые `breaks_between(start, end, true)` вычитает из интервала:
+    /// начало перерыва попадает в `(start, end]`.
+    pub fn break_windows(&self, start: NaiveDateTime, end: NaiveDateTime) -> Vec<BreakWindow> {
+        if end <= start {
+            return Vec::new();
+        }
+        self.occurrences(start)
+            .take_while(|window| window.start <= end + Duration::days(1))
+            .filter(|window| window.start > start && window.start <= end)
+            .collect()
+    }
+
+    fn max_break_duration(&self) -> Option<Duration> {
+        self.shifts
+            .iter()
+            .flat_map(|s| s.breaks.iter().map(|b| b.duration))
+            .max()
+    }
+
+    /// Бесконечная хронологическая последовательность перерывов, начиная с
+    /// смен предыдущего к `from` дня.
+    fn occurrences(&self, from: NaiveDateTime) -> impl Iterator<Item = BreakWindow> + '_ {
+        let first_day = from.date().pred_opt().unwrap_or(from.date());
+        let has_breaks = self.shifts.iter().any(|s| !s.breaks.is_empty());
+        first_day
+            .iter_days()
+            .take_while(move |_| has_breaks)
+            .flat_map(move |day| {
+                self.shifts.iter().flat_map(move |shift| {
+                    shift.breaks.iter().map(move |br| {
+                        let start = shift.starts_on(day) + shift.offset_of(br.start);
+                        BreakWindow {
+                            start,
+                            end: start + br.duration,
+                        }
+                    })
+                })
+            })
+    }
+}
+
+impl Default for ShiftCalendar {
+    /// Исторический график: дневная смена 07:00-19:00 и ночная 19:00-07:00.
+    fn default() -> Self {
+        let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let br = |h, m, d| Break {
+            start: t(h, m),
+            duration: Duration::minutes(d),
+        };
+        Self::new(vec![
+            Shift {
+                name: "Дневная".to_string(),
+                start: t(7, 0),
+                end: t(19, 0),
+                breaks: vec![br(9, 0, 15), br(12, 30, 30), br(15, 15, 15)],
+            },
+            Shift {
+                name: "Ночная".to_string(),
+                start: t(19, 0),
+                end: t(7, 0),
+                breaks: vec![br(22, 30, 30), br(1, 30, 30), br(4, 30, 30)],
+            },
+        ])
+        .expect("Стандартный график смен некорректен")
+    }
+}
+
+impl TryFrom<ShiftsSettings> for ShiftCalendar {
+    type Error = eyre::Error;
+
+    fn try_from(settings: ShiftsSettings) -> Result<Self> {
+        let shifts = settings
+            .list
+            .into_iter()
+            .map(Shift::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(shifts)
+    }
+}
+
+impl TryFrom<ShiftSettings> for Shift {
+    type Error = eyre::Error;
+
+    fn try_from(settings: ShiftSettings) -> Result<Self> {
+        let name = settings.name;
+        let start = to_time(&settings.start)
+            .map_err(|e| eyre!("Смена \"{}\": неверное время начала: {}", name, e))?;
+        let end = to_time(&settings.end)
+            .map_err(|e| eyre!("Смена \"{}\": неверное время окончания: {}", name, e))?;
+        let breaks = settings
+            .breaks
+            .iter()
+            .map(|b: &BreakSettings| {
+                Ok(Break {
+                    start: to_time(&b.start)
+                        .map_err(|e| eyre!("Смена \"{}\": неверное время перерыва: {}", name, e))?,
+                    duration: Duration::minutes(b.duration),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            name,
+            start,
+            end,
+            breaks,
+        })
+    }
+}
+
+fn to_time(time_str: &str) -> Result<NaiveTime> {
+    let (hour, minute) = parse_time(time_str)?;
+    NaiveTime::from_hms_opt(hour, minute, 0).ok_or_else(|| eyre!("{} вне диапазона", time_str))
+}
+
+fn minutes_between(from: NaiveTime, to: NaiveTime) -> i64 {
+    (to - from).num_minutes().rem_euclid(MINUTES_PER_DAY)
+}
+
+fn shifts_overlap(a: &Shift, b: &Shift) -> bool {
+    let a_start = minutes_between(NaiveTime::MIN, a.start);
+    let b_start = minutes_between(NaiveTime::MIN, b.start);
+    let a_end = a_start + a.length().num_minutes();
+    let b_end = b_start + b.length().num_minutes();
+    [-MINUTES_PER_DAY, 0, MINUTES_PER_DAY]
+        .iter()
+        .any(|shift| a_start < b_end + shift && b_start + shift < a_end)
+}
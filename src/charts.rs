@@ -0,0 +1,195 @@
+use crate::calendar::ShiftCalendar;
+use crate::models::PartData;
+use chrono::NaiveDateTime;
+use eyre::{eyre, Context, Result};
+use resvg::{tiny_skia, usvg};
+use serde::Serialize;
+use std::fmt::Write as FmtWrite;
+use std::sync::{Arc, OnceLock};
+
+const FONT_FAMILY: &str = "Calibri, Arial, sans-serif";
+const WIDTH: u32 = 600;
+const BAR_ROW: u32 = 22;
+const LABEL_WIDTH: u32 = 180;
+const TIMELINE_HEIGHT: u32 = 46;
+/// Во сколько раз PNG крупнее SVG, чтобы картинка была четкой на экранах с масштабом
+const PNG_SCALE: f32 = 2.0;
+
+/// График для HTML отчета. В письме он показывается картинкой PNG, вложенной под `cid`,
+/// в браузере и сохраненном отчете - как SVG.
+#[derive(Debug, Clone, Serialize)]
+pub struct Chart {
+    pub cid: String,
+    pub svg: String,
+    pub width: u32,
+    pub height: u32,
+    pub alt: String,
+}
+
+/// Горизонтальные столбцы превышения лимита по станкам, `None` - если превышений нет.
+pub fn overrun_chart(machines: &[(&str, i64)]) -> Option<Chart> {
+    let max = machines.iter().map(|&(_, overrun)| overrun).max()?;
+    if max <= 0 {
+        return None;
+    }
+    let height = machines.len() as u32 * BAR_ROW + 8;
+    let bar_area = (WIDTH - LABEL_WIDTH - 70) as f64;
+    let alt = "Превышение лимита наладки по станкам, мин.".to_string();
+
+    let mut svg = open_svg(WIDTH, height, &alt);
+    for (i, &(name, overrun)) in machines.iter().enumerate() {
+        let y = i as u32 * BAR_ROW + 4;
+        let bar = (overrun.max(0) as f64 / max as f64 * bar_area).max(1.0);
+        let _ = write!(
+            svg,
+            "<text x='{}' y='{}' text-anchor='end' font-size='12'>{}</text>\
+             <rect x='{}' y='{}' width='{:.1}' height='{}' fill='#d9534f'/>\
+             <text x='{:.1}' y='{}' font-size='12'>{}</text>",
+            LABEL_WIDTH - 6,
+            y + 14,
+            escape(name),
+            LABEL_WIDTH,
+            y + 2,
+            bar,
+            BAR_ROW - 6,
+            LABEL_WIDTH as f64 + bar + 4.0,
+            y + 14,
+            overrun
+        );
+    }
+    svg.push_str("</svg>");
+    Some(Chart {
+        cid: String::new(),
+        svg,
+        width: WIDTH,
+        height,
+        alt,
+    })
+}
+
+/// Полоса хода наладки от начала до запуска обработки: перерывы из графика смен
+/// затенены, промежутки между частями наладки оставлены пустыми.
+pub fn timeline_chart(part: &PartData, calendar: &ShiftCalendar, over_limit: bool) -> Chart {
+    let (start, end) = (part.start_setup_time, part.end_setup_time);
+    let total = (end - start).num_seconds().max(1) as f64;
+    let track = (WIDTH - 20) as f64;
+    let x = |at: NaiveDateTime| {
+        10.0 + ((at - start).num_seconds() as f64 / total * track).clamp(0.0, track)
+    };
+    let alt = format!(
+        "Наладка {} - {}, перерывы затенены",
+        start.format("%H:%M"),
+        end.format("%H:%M")
+    );
+
+    let mut svg = open_svg(WIDTH, TIMELINE_HEIGHT, &alt);
+    let color = if over_limit { "#d9534f" } else { "#5b8bd0" };
+    let _ = write!(
+        svg,
+        "<rect x='10' y='6' width='{track}' height='18' fill='{color}'/>"
+    );
+    for &(from, to) in &part.gaps {
+        let _ = write!(
+            svg,
+            "<rect x='{:.1}' y='6' width='{:.1}' height='18' fill='#ffffff' stroke='#cccccc'/>",
+            x(from),
+            x(to) - x(from)
+        );
+    }
+    for window in part.break_windows(calendar) {
+        let (from, to) = (x(window.start), x(window.end.min(end)));
+        let _ = write!(
+            svg,
+            "<rect x='{:.1}' y='6' width='{:.1}' height='18' fill='#555555' fill-opacity='0.45'>\
+             <title>Перерыв {} - {}</title></rect>",
+            from,
+            (to - from).max(1.0),
+            window.start.format("%H:%M"),
+            window.end.format("%H:%M")
+        );
+    }
+    let _ = write!(
+        svg,
+        "<text x='10' y='{y}' font-size='11'>{}</text>\
+         <text x='{}' y='{y}' text-anchor='end' font-size='11'>{}</text></svg>",
+        start.format("%d.%m %H:%M"),
+        WIDTH - 10,
+        end.format("%d.%m %H:%M"),
+        y = TIMELINE_HEIGHT - 8
+    );
+    Chart {
+        cid: String::new(),
+        svg,
+        width: WIDTH,
+        height: TIMELINE_HEIGHT,
+        alt,
+    }
+}
+
+fn open_svg(width: u32, height: u32, title: &str) -> String {
+    format!(
+        "<svg xmlns='http://www.w3.org/2000/svg' width='{width}' height='{height}' \
+         viewBox='0 0 {width} {height}' font-family='{FONT_FAMILY}' role='img'>\
+         <title>{}</title>",
+        escape(title)
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+/// Системные шрифты загружаются один раз за время работы программы.
+fn fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut db = usvg::fontdb::Database::new();
+            db.load_system_fonts();
+            // На сервере без Arial подписи рисуются любым установленным шрифтом
+            let query = usvg::fontdb::Query {
+                families: &[usvg::fontdb::Family::Name("Arial")],
+                ..Default::default()
+            };
+            if db.query(&query).is_none() {
+                let fallback = db
+                    .faces()
+                    .flat_map(|face| face.families.iter().map(|(name, _)| name))
+                    .find(|name| name.contains("Sans"))
+                    .or_else(|| {
+                        db.faces()
+                            .find_map(|face| face.families.first().map(|(name, _)| name))
+                    })
+                    .cloned();
+                if let Some(family) = fallback {
+                    db.set_sans_serif_family(family);
+                }
+            }
+            Arc::new(db)
+        })
+        .clone()
+}
+
+/// PNG с тем же изображением, что и SVG графика.
+pub fn render_png(chart: &Chart) -> Result<Vec<u8>> {
+    let options = usvg::Options {
+        fontdb: fonts(),
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_str(&chart.svg, &options).wrap_err("Ошибка разбора SVG")?;
+    let width = (chart.width as f32 * PNG_SCALE) as u32;
+    let height = (chart.height as f32 * PNG_SCALE) as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| eyre!("Неверный размер графика {}x{}", width, height))?;
+    pixmap.fill(tiny_skia::Color::WHITE);
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(PNG_SCALE, PNG_SCALE),
+        &mut pixmap.as_mut(),
+    );
+    pixmap.encode_png().wrap_err("Ошибка формирования PNG")
+}
@@ -0,0 +1,326 @@
+use async_smtp::EmailAddress;
+use chrono::{Local, NaiveDate};
+use clap::{Parser, Subcommand};
+use eyre::{eyre, Context, Result};
+use long_setups_reporter::config::Settings;
+use long_setups_reporter::history::{DeliveryStatus, History};
+use long_setups_reporter::init::{init_mailer, init_services, init_source};
+use long_setups_reporter::models::DateRange;
+use long_setups_reporter::norms::DailyNorms;
+use long_setups_reporter::outbox::{self, Outbox};
+use long_setups_reporter::reports::{generate_html_report, load_template, send_report_with_retry};
+use long_setups_reporter::watcher;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+#[derive(Debug, Parser)]
+#[command(name = "lsr", version, about = "Отчеты по длительным наладкам")]
+pub struct Cli {
+    /// Путь к файлу настроек вместо config/config.toml рядом с исполняемым файлом
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Работать по расписанию (режим по умолчанию)
+    Run,
+    /// Только оповещать о текущих наладках, превысивших лимит, без отчетов по расписанию
+    Watch,
+    /// Отправить отчет за вчерашний день немедленно
+    SendNow,
+    /// Сохранить HTML отчета в файл без отправки
+    Preview {
+        /// Сменная дата (ГГГГ-ММ-ДД), по умолчанию вчерашняя
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        /// Файл для сохранения HTML
+        #[arg(long, default_value = "preview.html")]
+        output: PathBuf,
+    },
+    /// Проверить файл настроек и вывести параметры
+    CheckConfig,
+    /// Проверить подключение к источнику данных
+    TestDb,
+    /// Проверить подключение и авторизацию на почтовом сервере
+    TestSmtp,
+    /// Показать, какое правило лимита наладки применяется к станку и детали
+    ExplainLimit {
+        /// Название станка
+        machine: String,
+        /// Название детали
+        part: String,
+        /// Номер установки
+        #[arg(long, default_value_t = 1)]
+        setup: i32,
+    },
+    /// Показать журнал последних отправок отчетов
+    History {
+        /// Количество записей
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Управлять очередью неотправленных писем
+    Outbox {
+        #[command(subcommand)]
+        action: OutboxCommand,
+    },
+    /// Сформировать отчет за произвольный период
+    Report {
+        /// Первая сменная дата периода (ГГГГ-ММ-ДД)
+        #[arg(long)]
+        from: NaiveDate,
+        /// Последняя сменная дата периода (ГГГГ-ММ-ДД), по умолчанию равна --from
+        #[arg(long)]
+        to: Option<NaiveDate>,
+        /// Отправить отчет получателям из настроек
+        #[arg(long)]
+        send: bool,
+        /// Файл для сохранения HTML, если отчет не отправляется
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum OutboxCommand {
+    /// Показать письма в очереди
+    List,
+    /// Отправить письма из очереди немедленно
+    Retry {
+        /// Идентификатор письма, по умолчанию все письма
+        id: Option<String>,
+    },
+    /// Удалить письма из очереди без отправки, досылка их не повторяет
+    Purge {
+        /// Идентификатор письма
+        id: Option<String>,
+        /// Удалить все письма
+        #[arg(long, conflicts_with = "id")]
+        all: bool,
+    },
+}
+
+pub async fn send_now(settings: &Settings) -> Result<()> {
+    let period = DateRange::yesterday();
+    let services = init_services(settings).await?;
+    let status =
+        send_report_with_retry(&services, settings, "send_now", &period, &settings.smtp.to).await?;
+    report_status(&period, status);
+    Ok(())
+}
+
+pub async fn watch(settings: Settings) -> Result<()> {
+    let services = init_services(&settings).await?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Получен сигнал Ctrl+C, завершение работы..."),
+        _ = watcher::watch(services.clone(), settings.clone()) => {}
+        _ = outbox::run(services, settings) => {}
+    }
+    Ok(())
+}
+
+pub async fn preview(settings: &Settings, date: Option<NaiveDate>, output: &Path) -> Result<()> {
+    let period = date.map_or_else(DateRange::yesterday, DateRange::single_day);
+    save_report(settings, &period, output).await
+}
+
+pub fn check_config(settings: &Settings) -> Result<()> {
+    settings
+        .smtp
+        .from
+        .parse::<EmailAddress>()
+        .map_err(|e| eyre!("Неверный адрес отправителя {}: {}", settings.smtp.from, e))?;
+    let schedule_recipients = settings.schedules.iter().flat_map(|s| &s.to);
+    let group_recipients = settings.recipient_groups.iter().flat_map(|g| &g.to);
+    for to in settings
+        .smtp
+        .to
+        .iter()
+        .chain(schedule_recipients)
+        .chain(group_recipients)
+        .chain(&settings.report.fallback_to)
+    {
+        to.parse::<EmailAddress>()
+            .map_err(|e| eyre!("Неверный адрес получателя {}: {}", to, e))?;
+    }
+    let template = load_template(settings)?;
+    minijinja::Environment::new()
+        .template_from_str(&template)
+        .wrap_err("Ошибка разбора шаблона отчета")?;
+    println!("{settings}");
+    info!("Файл настроек {} корректен", settings.path.display());
+    Ok(())
+}
+
+pub async fn test_db(settings: &Settings) -> Result<()> {
+    let source = init_source(settings).await?;
+    source.lock().await.ping().await?;
+    info!("Источник данных отвечает на запросы");
+    Ok(())
+}
+
+pub async fn test_smtp(settings: &Settings) -> Result<()> {
+    let mailer = init_mailer(settings).await?;
+    mailer.lock().await.login().await?;
+    info!("Авторизация на почтовом сервере выполнена");
+    Ok(())
+}
+
+pub fn explain_limit(settings: &Settings, machine: &str, part: &str, setup: i32) {
+    let found = settings.find_setup_limit(machine, part, setup);
+    println!("Станок: {machine}\nДеталь: {part}\nУстановка: {setup}\n");
+
+    let matching: Vec<_> = settings
+        .setup_limits
+        .matching(machine, part, setup)
+        .collect();
+    if matching.is_empty() {
+        println!("Подходящих правил нет");
+    } else {
+        println!("Подходящие правила по приоритету:");
+        for (i, rule) in matching.iter().enumerate() {
+            let marker = if i == 0 { "*" } else { " " };
+            println!("  {marker} {rule}");
+        }
+    }
+
+    match found.rule {
+        Some(rule) => println!("\nЛимит: {} мин, правило [{}]", found.limit, rule.origin),
+        None => println!("\nЛимит: {} мин, report.default_setup_limit", found.limit),
+    }
+}
+
+pub fn history(settings: &Settings, limit: usize) -> Result<()> {
+    let history = History::open(&History::path(settings))?;
+    let deliveries = history.deliveries(limit)?;
+    if deliveries.is_empty() {
+        println!("Журнал отправок пуст");
+    }
+    for d in deliveries {
+        println!(
+            "{} [{}] {} за {}: {:?}, наладок {}, кому: {}",
+            d.created_at.format("%d.%m.%Y %H:%M:%S"),
+            d.id,
+            d.name,
+            d.period,
+            d.status,
+            d.setups,
+            d.recipients.join(", ")
+        );
+        println!("    {}", d.subject);
+        if let Some(error) = d.error {
+            println!("    {error}");
+        }
+    }
+    Ok(())
+}
+
+pub async fn report(
+    settings: &Settings,
+    from: NaiveDate,
+    to: Option<NaiveDate>,
+    send: bool,
+    output: Option<PathBuf>,
+) -> Result<()> {
+    let period = DateRange::new(from, to.unwrap_or(from))?;
+
+    if send {
+        let services = init_services(settings).await?;
+        let status =
+            send_report_with_retry(&services, settings, "report", &period, &settings.smtp.to)
+                .await?;
+        report_status(&period, status);
+        return Ok(());
+    }
+
+    let path = output.unwrap_or_else(|| {
+        PathBuf::from(format!(
+            "report_{}_{}.html",
+            period.from.format("%Y-%m-%d"),
+            period.to.format("%Y-%m-%d")
+        ))
+    });
+    save_report(settings, &period, &path).await
+}
+
+pub async fn outbox(settings: &Settings, action: OutboxCommand) -> Result<()> {
+    match action {
+        OutboxCommand::List => {
+            let entries = Outbox::open(&Outbox::path(settings))?.entries()?;
+            if entries.is_empty() {
+                println!("Очередь писем пуста");
+            }
+            for entry in entries {
+                println!(
+                    "{}\n    {}\n    кому: {}\n    создано {}, попыток {}, следующая {}",
+                    entry.id,
+                    entry.subject,
+                    entry.to.join(", "),
+                    entry.created_at.format("%d.%m.%Y %H:%M:%S"),
+                    entry.attempts,
+                    entry.next_attempt.format("%d.%m.%Y %H:%M:%S")
+                );
+                if let Some(error) = entry.last_error {
+                    println!("    ошибка: {error}");
+                }
+                if entry.expired {
+                    println!("    больше не повторяется автоматически, отправка только по lsr outbox retry");
+                }
+            }
+        }
+        OutboxCommand::Retry { id } => {
+            let services = init_services(settings).await?;
+            let (sent, left) = outbox::flush(&services, settings, true, id.as_deref()).await?;
+            info!("Отправлено писем: {}, осталось в очереди: {}", sent, left);
+        }
+        OutboxCommand::Purge { id, all } => {
+            if id.is_none() && !all {
+                return Err(eyre!("Укажите идентификатор письма или --all"));
+            }
+            let outbox = Outbox::open(&Outbox::path(settings))?;
+            let history = History::open(&History::path(settings))?;
+            let removed = outbox::purge(&outbox, &history, id.as_deref())?;
+            info!("Удалено писем из очереди: {}", removed);
+        }
+    }
+    Ok(())
+}
+
+fn report_status(period: &DateRange, status: DeliveryStatus) {
+    match status {
+        DeliveryStatus::Queued => info!(
+            "Отчёт за {} поставлен в очередь, см. lsr outbox list",
+            period
+        ),
+        DeliveryStatus::Empty => info!("Отчёт за {} не отправлен: отправлять нечего", period),
+        _ => info!("Отчёт за {} отправлен", period),
+    }
+}
+
+async fn save_report(settings: &Settings, period: &DateRange, path: &Path) -> Result<()> {
+    let source = init_source(settings).await?;
+    let mut settings = settings.clone();
+    DailyNorms::default()
+        .apply(&source, &mut settings, Local::now().naive_local())
+        .await;
+    let settings = &settings;
+    let data = source
+        .lock()
+        .await
+        .fetch_report_data(settings, period)
+        .await?;
+    let html = generate_html_report(&data, settings)?;
+    std::fs::write(path, html)
+        .wrap_err_with(|| format!("Не удалось сохранить отчет в {}", path.display()))?;
+    info!(
+        "Отчёт за {} сохранен в {} (наладок: {})",
+        period,
+        path.display(),
+        data.len()
+    );
+    Ok(())
+}
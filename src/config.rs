@@ -1,25 +1,195 @@
 use core::fmt;
-use std::{collections::HashMap, env, path::PathBuf};
+use std::{
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use chrono::NaiveDate;
 use config::Config;
 use serde::Deserialize;
 
+use crate::calendar::ShiftCalendar;
+use crate::limits::{LimitMatch, LimitRules};
+use crate::models::DateRange;
+use crate::norms::{Norm, Norms};
+use crate::pattern::NamePattern;
+use crate::scheduler::CronSchedule;
+use crate::utils::RetryPolicy;
+
 const WIDTH: usize = 30;
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
+    #[serde(default)]
+    pub source: SourceSettings,
+    #[serde(default)]
     pub database: DatabaseSettings,
     pub smtp: SmtpSettings,
     pub report: ReportSettings,
     pub general: GeneralSettings,
     pub limits: HashMap<String, i32>,
+    #[serde(default)]
+    pub limit_rules: Vec<LimitRuleSettings>,
+    #[serde(default)]
+    pub time_policies: Vec<TimePolicySettings>,
+    #[serde(default)]
+    pub recipient_groups: Vec<RecipientGroup>,
+    #[serde(default)]
+    pub shifts: ShiftCalendar,
+    #[serde(default)]
+    pub schedules: Vec<ScheduleSettings>,
+    #[serde(default)]
+    pub alerts: AlertSettings,
+    #[serde(default)]
+    pub history: HistorySettings,
+    #[serde(default)]
+    pub outbox: OutboxSettings,
+    #[serde(default)]
+    pub norms: NormSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(skip)]
+    pub setup_limits: LimitRules,
+    /// Нормы, рассчитанные по истории, см. `norms::DailyNorms`
+    #[serde(skip)]
+    pub learned_norms: Arc<Norms>,
+    #[serde(skip)]
+    pub path: PathBuf,
 }
 
+/// Откуда берутся данные о наладках.
 #[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SourceSettings {
+    pub kind: SourceKind,
+    /// Для `csv` - файл или папка с CSV-файлами, для `sqlite` - файл базы
+    pub path: Option<PathBuf>,
+    pub delimiter: char,
+    /// Формат даты и времени в CSV, см. `chrono::format::strftime`
+    pub datetime_format: String,
+    /// Таблица или представление с наладками, можно со схемой: `dbo.parts`
+    pub table: String,
+    /// Дополнительные условия WHERE на SQL, объединяются через AND
+    pub filters: Vec<String>,
+    pub columns: ColumnMapping,
+}
+
+/// Имена столбцов источника для каждого поля наладки.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ColumnMapping {
+    pub part_name: String,
+    pub setup: String,
+    pub order: String,
+    pub machine: String,
+    pub operator: String,
+    pub start_setup_time: String,
+    pub start_machining_time: String,
+    pub setup_downtimes: String,
+    pub operator_comment: String,
+    pub shift_date: String,
+}
+
+/// Столбец источника: ключ в `source.columns`, имя столбца в таблице `parts`
+/// и имя, под которым он есть в источнике.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedColumn<'a> {
+    pub key: &'static str,
+    pub field: &'static str,
+    pub name: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// Таблица `parts` в SQL Server из `[database]`
+    #[default]
+    SqlServer,
+    /// Выгрузка в CSV
+    Csv,
+    /// Таблица `parts` в файле SQLite
+    Sqlite,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct DatabaseSettings {
+    /// Имя или адрес сервера, допускаются формы `host\instance` и `host,port`
     pub host: String,
+    /// Именованный экземпляр, порт которого определяется через SQL Server Browser
+    pub instance: Option<String>,
+    /// Порт, если задан, используется вместо SQL Server Browser
+    pub port: Option<u16>,
     pub username: String,
     pub password: String,
     pub database: String,
+    #[serde(default)]
+    pub encryption: DbEncryption,
+    /// Не проверять сертификат сервера
+    #[serde(default)]
+    pub trust_cert: bool,
+    /// Сертификат (pem, crt или der), которому доверять помимо системных
+    pub ca_file: Option<PathBuf>,
+}
+
+/// Шифрование соединения с SQL Server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbEncryption {
+    /// Шифруется только вход
+    Off,
+    /// Шифровать все, если сервер поддерживает
+    On,
+    /// Шифровать все, без шифрования не подключаться
+    #[default]
+    Required,
+}
+
+/// Куда подключаться: сервер, экземпляр и порт.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbAddress {
+    pub host: String,
+    pub instance: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl DatabaseSettings {
+    /// Адрес из `host` с учетом `instance` и `port`, которые важнее указанных в `host`.
+    pub fn address(&self) -> Result<DbAddress, String> {
+        let (rest, port) = match self.host.split_once(',') {
+            Some((rest, port)) => {
+                let port = port
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Неверный порт в database.host: {}", self.host))?;
+                (rest, Some(port))
+            }
+            None => (self.host.as_str(), None),
+        };
+        let (host, instance) = match rest.split_once('\\') {
+            Some((host, instance)) => (host, Some(instance.to_string())),
+            None => (rest, None),
+        };
+        Ok(DbAddress {
+            host: host.trim().to_string(),
+            instance: self.instance.clone().or(instance),
+            port: self.port.or(port),
+        })
+    }
+}
+
+impl fmt::Display for DbAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.host)?;
+        if let Some(instance) = &self.instance {
+            write!(f, "\\{instance}")?;
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -30,22 +200,425 @@ pub struct SmtpSettings {
     pub password: String,
     pub from: String,
     pub to: Vec<String>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// Сертификаты в формате PEM, которым доверять помимо системных
+    pub ca_file: Option<PathBuf>,
+    /// Разрешить передачу пароля по незашифрованному соединению
+    #[serde(default)]
+    pub allow_insecure_auth: bool,
+}
+
+/// Шифрование соединения с почтовым сервером.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Без шифрования
+    None,
+    /// Обычное подключение, переводимое в TLS командой STARTTLS (обычно порт 587 или 25)
+    #[default]
+    Starttls,
+    /// TLS с момента подключения (обычно порт 465)
+    Tls,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ReportSettings {
-    pub send_time: String, // Format "HH:MM"
+    pub send_time: Option<String>, // Format "HH:MM", если не заданы [[schedules]]
     pub default_setup_limit: i64,
+    /// За сколько последних дней досылать пропущенные отчеты, 0 - не досылать
+    #[serde(default = "default_catch_up_days")]
+    pub catch_up_days: i64,
+    /// Собирать наладку, записанную несколькими строками, в одну перед проверкой лимита
+    #[serde(default = "default_true")]
+    pub merge_split_setups: bool,
+    /// Получатели наладок на станках, не попавших ни в одну `[[recipient_groups]]`.
+    /// Обязателен, если группы заданы
+    #[serde(default)]
+    pub fallback_to: Vec<String>,
+    /// Расчет чистого времени наладки для станков, не попавших в `[[time_policies]]`
+    #[serde(default)]
+    pub time_policy: TimePolicy,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentFormat>,
+    /// Графики в HTML отчете: превышение по станкам и ход каждой наладки
+    #[serde(default = "default_true")]
+    pub charts: bool,
+    pub template: Option<PathBuf>,
+    #[serde(default)]
+    pub machine_order: MachineOrder,
+    #[serde(default)]
+    pub workshop_order: Vec<NamePattern>,
+    #[serde(default)]
+    pub part_order: PartOrder,
+}
+
+/// Что вычитается из длительности наладки перед сравнением с лимитом.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimePolicy {
+    /// Ничего: от начала наладки до запуска обработки
+    Gross,
+    /// Перерывы по графику смен
+    #[default]
+    MinusBreaks,
+    /// Перерывы и простои, записанные оператором (`SetupDowntimes`)
+    MinusBreaksAndDowntimes,
+}
+
+impl TimePolicy {
+    pub fn description(&self) -> &'static str {
+        match self {
+            TimePolicy::Gross => "с перерывами и простоями",
+            TimePolicy::MinusBreaks => "без перерывов",
+            TimePolicy::MinusBreaksAndDowntimes => "без перерывов и простоев",
+        }
+    }
+}
+
+/// Расчет времени наладки для группы станков, см. `[[time_policies]]`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TimePolicySettings {
+    pub machine: NamePattern,
+    pub policy: TimePolicy,
+}
+
+/// Получатели отчета только по своим станкам, например мастер участка.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RecipientGroup {
+    pub name: String,
+    pub machines: Vec<NamePattern>,
+    pub to: Vec<String>,
+}
+
+impl RecipientGroup {
+    pub fn is_match(&self, machine: &str) -> bool {
+        self.machines.iter().any(|p| p.is_match(machine))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineOrder {
+    #[default]
+    Alphabetical,
+    Workshop,
+    Overrun,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PartOrder {
+    #[default]
+    StartTime,
+    Overrun,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentFormat {
+    Csv,
+    Xlsx,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LimitRuleSettings {
+    pub machine: Option<NamePattern>,
+    pub part: Option<NamePattern>,
+    pub setup: Option<i32>,
+    pub limit: i64, // Minutes
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShiftsSettings {
+    pub list: Vec<ShiftSettings>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShiftSettings {
+    pub name: String,
+    pub start: String, // Format "HH:MM"
+    pub end: String,   // Format "HH:MM"
+    #[serde(default)]
+    pub breaks: Vec<BreakSettings>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BreakSettings {
+    pub start: String, // Format "HH:MM"
+    pub duration: i64, // Minutes
+}
+
+/// Расписание отправки: когда, какой отчет, за какой период и кому.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScheduleSettings {
+    pub name: String,
+    pub cron: CronSchedule,
+    #[serde(default)]
+    pub kind: ReportKind,
+    #[serde(default)]
+    pub period: ReportPeriod,
+    /// Пустой список означает получателей из `smtp.to`
+    #[serde(default)]
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportKind {
+    /// Список длительных наладок
+    #[default]
+    Setups,
+    /// Сводка по станкам со сравнением с предыдущим периодом
+    Trend,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportPeriod {
+    #[default]
+    Yesterday,
+    PreviousWeek,
+    PreviousMonth,
+}
+
+/// Оповещения о наладках, которые еще идут и уже превысили лимит.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AlertSettings {
+    pub enabled: bool,
+    pub poll_interval: u64, // Minutes
+    /// Наладки, начатые раньше, считаются брошенными и не отслеживаются
+    pub lookback_hours: i64,
+    /// Пустой список означает получателей из `smtp.to`
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct HistorySettings {
+    /// Файл журнала SQLite, по умолчанию `history.sqlite` рядом с исполняемым файлом
+    pub path: Option<PathBuf>,
+}
+
+/// Нормы времени наладки, рассчитанные по истории наладок.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct NormSettings {
+    /// Рассчитывать нормы и показывать их в отчете рядом с лимитом
+    pub enabled: bool,
+    /// Проверять наладки по норме вместо лимита, если истории достаточно
+    pub as_limit: bool,
+    /// За сколько дней до текущего брать историю
+    pub lookback_days: i64,
+    /// Минимум наладок детали на станке, чтобы норме можно было доверять
+    pub min_samples: usize,
+    /// Запас сверх p90 в процентах, после которого наладка считается длительной
+    pub margin_percent: f64,
+}
+
+/// Встроенный веб-интерфейс с панелью наладок и JSON API.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub enabled: bool,
+    /// Адрес и порт, например `127.0.0.1:8080` или `0.0.0.0:8080` для доступа из сети
+    pub listen: String,
+    /// Самый длинный период одного запроса, дней: запрос целиком читается из источника
+    pub max_days: i64,
+}
+
+/// Очередь писем, которые не удалось отправить сразу.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct OutboxSettings {
+    /// Папка очереди, по умолчанию `outbox` рядом с исполняемым файлом
+    pub path: Option<PathBuf>,
+    pub retry_interval: i64,     // Minutes
+    pub max_retry_interval: i64, // Minutes
+    /// Письма старше этого срока больше не повторяются автоматически, но остаются
+    /// в очереди до `lsr outbox retry` или `lsr outbox purge`. Без значения повторяются всегда
+    pub max_age_hours: Option<i64>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct GeneralSettings {
     pub log_level: String,
     pub send_delay: i32,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+fn default_catch_up_days() -> i64 {
+    7
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl AttachmentFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AttachmentFormat::Csv => "csv",
+            AttachmentFormat::Xlsx => "xlsx",
+        }
+    }
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            poll_interval: 5,
+            lookback_hours: 24,
+            to: Vec::new(),
+        }
+    }
+}
+
+impl Default for SourceSettings {
+    fn default() -> Self {
+        Self {
+            kind: SourceKind::SqlServer,
+            path: None,
+            delimiter: ',',
+            datetime_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            table: "parts".to_string(),
+            filters: Vec::new(),
+            columns: ColumnMapping::default(),
+        }
+    }
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            part_name: "PartName".to_string(),
+            setup: "Setup".to_string(),
+            order: "Order".to_string(),
+            machine: "Machine".to_string(),
+            operator: "Operator".to_string(),
+            start_setup_time: "StartSetupTime".to_string(),
+            start_machining_time: "StartMachiningTime".to_string(),
+            setup_downtimes: "SetupDowntimes".to_string(),
+            operator_comment: "OperatorComment".to_string(),
+            shift_date: "ShiftDate".to_string(),
+        }
+    }
+}
+
+impl ColumnMapping {
+    /// Все столбцы в порядке таблицы `parts`.
+    pub fn all(&self) -> [MappedColumn<'_>; 10] {
+        fn column<'a>(key: &'static str, field: &'static str, name: &'a str) -> MappedColumn<'a> {
+            MappedColumn { key, field, name }
+        }
+        [
+            column("part_name", "PartName", &self.part_name),
+            column("setup", "Setup", &self.setup),
+            column("order", "Order", &self.order),
+            column("machine", "Machine", &self.machine),
+            column("operator", "Operator", &self.operator),
+            column("start_setup_time", "StartSetupTime", &self.start_setup_time),
+            column(
+                "start_machining_time",
+                "StartMachiningTime",
+                &self.start_machining_time,
+            ),
+            column("setup_downtimes", "SetupDowntimes", &self.setup_downtimes),
+            column(
+                "operator_comment",
+                "OperatorComment",
+                &self.operator_comment,
+            ),
+            column("shift_date", "ShiftDate", &self.shift_date),
+        ]
+    }
+}
+
+impl Default for NormSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            as_limit: false,
+            lookback_days: 180,
+            min_samples: 10,
+            margin_percent: 20.0,
+        }
+    }
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_string(),
+            max_days: 92,
+        }
+    }
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            path: None,
+            retry_interval: 5,
+            max_retry_interval: 120,
+            max_age_hours: None,
+        }
+    }
+}
+
+impl AlertSettings {
+    pub fn recipients<'a>(&'a self, smtp: &'a SmtpSettings) -> &'a [String] {
+        if self.to.is_empty() {
+            &smtp.to
+        } else {
+            &self.to
+        }
+    }
+}
+
+impl ScheduleSettings {
+    /// Ежедневное расписание из устаревшего `report.send_time`.
+    fn legacy(send_time: &str) -> eyre::Result<Self> {
+        let (hour, minute) = crate::utils::parse_time(send_time)?;
+        Ok(Self {
+            name: "daily".to_string(),
+            cron: CronSchedule::new(&format!("{minute} {hour} * * *"))?,
+            kind: ReportKind::default(),
+            period: ReportPeriod::default(),
+            to: Vec::new(),
+        })
+    }
+
+    pub fn recipients<'a>(&'a self, smtp: &'a SmtpSettings) -> &'a [String] {
+        if self.to.is_empty() {
+            &smtp.to
+        } else {
+            &self.to
+        }
+    }
+}
+
+impl ReportPeriod {
+    /// Период отчета, отправляемого в день `today`.
+    pub fn range(&self, today: NaiveDate) -> DateRange {
+        match self {
+            ReportPeriod::Yesterday => DateRange::single_day(today.pred_opt().unwrap_or(today)),
+            ReportPeriod::PreviousWeek => DateRange::previous_week(today),
+            ReportPeriod::PreviousMonth => DateRange::previous_month(today),
+        }
+    }
 }
 
 impl Settings {
     pub fn new() -> Result<Self, config::ConfigError> {
+        Self::from_file(&Self::default_path()?)
+    }
+
+    /// Путь к настройкам по умолчанию: `config/config.toml` рядом с исполняемым файлом.
+    pub fn default_path() -> Result<PathBuf, config::ConfigError> {
         let exe_dir = match env::current_exe() {
             Ok(path) => path.parent().map(PathBuf::from),
             Err(e) => {
@@ -62,15 +635,160 @@ impl Settings {
             )
         })?;
         config_path.push("config/config.toml");
+        Ok(config_path)
+    }
 
-        let cfg = Config::builder()
-            .add_source(config::File::with_name(config_path.to_str().unwrap()))
-            .build()?;
-        cfg.try_deserialize()
+    pub fn from_file(path: &Path) -> Result<Self, config::ConfigError> {
+        let mut settings = Self::load(config::File::from(path))?;
+        settings.path = path.to_path_buf();
+        settings.source.path = settings
+            .source
+            .path
+            .as_deref()
+            .map(|path| settings.resolve_path(path));
+        settings.database.ca_file = settings
+            .database
+            .ca_file
+            .as_deref()
+            .map(|ca_file| settings.resolve_path(ca_file));
+        settings.smtp.ca_file = settings
+            .smtp
+            .ca_file
+            .as_deref()
+            .map(|ca_file| settings.resolve_path(ca_file));
+        Ok(settings)
     }
+
+    pub(crate) fn load<S>(source: S) -> Result<Self, config::ConfigError>
+    where
+        S: config::Source + Send + Sync + 'static,
+    {
+        let cfg = Config::builder().add_source(source).build()?;
+        let mut settings: Settings = cfg.try_deserialize()?;
+        settings.setup_limits = LimitRules::new(
+            &settings.limits,
+            &settings.limit_rules,
+            settings.report.default_setup_limit,
+        )
+        .map_err(|e| config::ConfigError::Message(e.to_string()))?;
+        if settings.alerts.poll_interval == 0 {
+            return Err(config::ConfigError::Message(
+                "alerts.poll_interval должен быть положительным".to_string(),
+            ));
+        }
+        if settings.norms.lookback_days <= 0
+            || settings.norms.min_samples == 0
+            || settings.norms.margin_percent < 0.0
+        {
+            return Err(config::ConfigError::Message(
+                "norms.lookback_days и norms.min_samples должны быть положительными, \
+                 norms.margin_percent - не меньше 0"
+                    .to_string(),
+            ));
+        }
+        if settings.outbox.retry_interval <= 0
+            || settings.outbox.max_retry_interval < settings.outbox.retry_interval
+        {
+            return Err(config::ConfigError::Message(
+                "outbox.retry_interval должен быть положительным и не больше outbox.max_retry_interval"
+                    .to_string(),
+            ));
+        }
+        if settings
+            .outbox
+            .max_age_hours
+            .is_some_and(|hours| hours <= 0)
+        {
+            return Err(config::ConfigError::Message(
+                "outbox.max_age_hours должен быть положительным".to_string(),
+            ));
+        }
+        if settings.http.listen.parse::<SocketAddr>().is_err() {
+            return Err(config::ConfigError::Message(format!(
+                "Неверный адрес http.listen: {}",
+                settings.http.listen
+            )));
+        }
+        if settings.http.max_days <= 0 {
+            return Err(config::ConfigError::Message(
+                "http.max_days должен быть положительным".to_string(),
+            ));
+        }
+        if let Some(column) = settings
+            .source
+            .columns
+            .all()
+            .into_iter()
+            .find(|column| column.name.trim().is_empty())
+        {
+            return Err(config::ConfigError::Message(format!(
+                "Пустое имя столбца source.columns.{}",
+                column.key
+            )));
+        }
+        if settings.source.kind != SourceKind::SqlServer && settings.source.path.is_none() {
+            return Err(config::ConfigError::Message(format!(
+                "Для источника {:?} нужно указать source.path",
+                settings.source.kind
+            )));
+        }
+        settings
+            .database
+            .address()
+            .map_err(config::ConfigError::Message)?;
+        if settings.database.trust_cert && settings.database.ca_file.is_some() {
+            return Err(config::ConfigError::Message(
+                "database.trust_cert и database.ca_file нельзя задавать одновременно".to_string(),
+            ));
+        }
+        if settings.smtp.security == SmtpSecurity::None
+            && !settings.smtp.username.is_empty()
+            && !settings.smtp.allow_insecure_auth
+        {
+            return Err(config::ConfigError::Message(
+                "smtp.security = \"none\": пароль будет передан без шифрования. \
+                 Включите starttls или tls, либо разрешите это через smtp.allow_insecure_auth"
+                    .to_string(),
+            ));
+        }
+        for group in &settings.recipient_groups {
+            if group.machines.is_empty() || group.to.is_empty() {
+                return Err(config::ConfigError::Message(format!(
+                    "В группе получателей \"{}\" должны быть заданы machines и to",
+                    group.name
+                )));
+            }
+        }
+        // Станок, не попавший ни в одну группу, не должен остаться без получателя
+        if !settings.recipient_groups.is_empty() && settings.report.fallback_to.is_empty() {
+            return Err(config::ConfigError::Message(
+                "При заданных [[recipient_groups]] нужно указать report.fallback_to \
+                 для станков вне групп"
+                    .to_string(),
+            ));
+        }
+        settings
+            .general
+            .retry
+            .validate()
+            .map_err(|e| config::ConfigError::Message(e.to_string()))?;
+        if settings.schedules.is_empty() {
+            let send_time = settings.report.send_time.as_deref().ok_or_else(|| {
+                config::ConfigError::Message(
+                    "Не задано ни report.send_time, ни [[schedules]]".to_string(),
+                )
+            })?;
+            let legacy = ScheduleSettings::legacy(send_time).map_err(|e| {
+                config::ConfigError::Message(format!("Неверное report.send_time: {e}"))
+            })?;
+            settings.schedules.push(legacy);
+        }
+        Ok(settings)
+    }
+
     pub fn update(&mut self) -> Result<Self, config::ConfigError> {
         let old_settings = self.clone();
-        match Settings::new() {
+        match Settings::from_file(&self.path) {
             Ok(new_settings) => {
                 *self = new_settings.clone();
                 Ok(new_settings)
@@ -81,20 +799,73 @@ impl Settings {
             }
         }
     }
-    pub fn get_setup_limit(&self, machine: &str) -> i32 {
-        self.limits
-            .get(&machine.to_lowercase())
-            .copied()
-            .unwrap_or(240)
+    /// Относительные пути в настройках отсчитываются от папки с файлом настроек.
+    pub fn resolve_path(&self, path: &Path) -> PathBuf {
+        match self.path.parent() {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Лимит наладки: при `norms.as_limit` - норма по истории с запасом, если
+    /// наладок было достаточно, иначе лимит из правил.
+    pub fn get_setup_limit(&self, machine: &str, part: &str, setup: i32) -> i64 {
+        match self.norm(machine, part, setup) {
+            Some(norm) if self.norms.as_limit => norm.limit(self.norms.margin_percent),
+            _ => self.find_setup_limit(machine, part, setup).limit,
+        }
+    }
+
+    /// Норма по истории, если по ней набралось не меньше `norms.min_samples` наладок.
+    pub fn norm(&self, machine: &str, part: &str, setup: i32) -> Option<&Norm> {
+        self.learned_norms
+            .get(machine, part, setup)
+            .filter(|norm| norm.count >= self.norms.min_samples)
+    }
+
+    pub fn find_setup_limit(&self, machine: &str, part: &str, setup: i32) -> LimitMatch<'_> {
+        self.setup_limits.find(machine, part, setup)
+    }
+
+    /// Расчет времени наладки для станка: первое подходящее правило
+    /// `[[time_policies]]`, иначе `report.time_policy`.
+    pub fn time_policy(&self, machine: &str) -> TimePolicy {
+        self.time_policies
+            .iter()
+            .find(|rule| rule.machine.is_match(machine))
+            .map_or(self.report.time_policy, |rule| rule.policy)
     }
 }
 
 impl fmt::Display for Settings {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "\n{:<WIDTH$}{}", "Файл настроек:", self.path.display())?;
+
+        writeln!(f, "\nИсточник данных:")?;
+        writeln!(f, "  {:<WIDTH$}{:?}", "Тип:", self.source.kind)?;
+        if let Some(path) = &self.source.path {
+            writeln!(f, "  {:<WIDTH$}{}", "Путь:", path.display())?;
+        }
+
         writeln!(f, "\nБаза данных:")?;
-        writeln!(f, "  {:<WIDTH$}{}", "Сервер:", self.database.host)?;
+        match self.database.address() {
+            Ok(address) => writeln!(f, "  {:<WIDTH$}{}", "Сервер:", address)?,
+            Err(_) => writeln!(f, "  {:<WIDTH$}{}", "Сервер:", self.database.host)?,
+        }
         writeln!(f, "  {:<WIDTH$}{}", "База:", self.database.database)?;
+        writeln!(
+            f,
+            "  {:<WIDTH$}{:?}, {}",
+            "Шифрование:",
+            self.database.encryption,
+            match (&self.database.ca_file, self.database.trust_cert) {
+                (Some(ca), _) => format!("сертификат из {}", ca.display()),
+                (None, true) => "сертификат не проверяется".to_string(),
+                (None, false) => "сертификат проверяется".to_string(),
+            }
+        )?;
         writeln!(f, "  {:<WIDTH$}{}", "Пользователь:", self.database.username)?;
+        #[allow(clippy::write_literal)]
         writeln!(f, "  {:<WIDTH$}{}", "Пароль:", "********")?;
 
         writeln!(f, "\nПочтовый сервер:")?;
@@ -103,26 +874,235 @@ impl fmt::Display for Settings {
             "  {:<WIDTH$}{}:{}",
             "Сервер:", self.smtp.server, self.smtp.port
         )?;
+        writeln!(
+            f,
+            "  {:<WIDTH$}{:?}{}",
+            "Шифрование:",
+            self.smtp.security,
+            self.smtp
+                .ca_file
+                .as_ref()
+                .map(|ca| format!(", сертификаты из {}", ca.display()))
+                .unwrap_or_default()
+        )?;
         writeln!(f, "  {:<WIDTH$}{}", "От кого:", self.smtp.from)?;
         writeln!(f, "  {:<WIDTH$}{}", "Кому:", self.smtp.to.join(", "))?;
         writeln!(f, "  {:<WIDTH$}{}", "Пользователь:", self.smtp.username)?;
+        #[allow(clippy::write_literal)]
         writeln!(f, "  {:<WIDTH$}{}", "Пароль:", "********")?;
 
         writeln!(f, "\nНастройки отчета:")?;
         writeln!(
             f,
             "  {:<WIDTH$}{}",
-            "Время отправки:", self.report.send_time
+            "Лимит наладки по умолчанию:", self.report.default_setup_limit
         )?;
         writeln!(
             f,
             "  {:<WIDTH$}{}",
-            "Лимит наладки по умолчанию:", self.report.default_setup_limit
+            "Досылать пропущенные, дней:", self.report.catch_up_days
+        )?;
+        writeln!(
+            f,
+            "  {:<WIDTH$}{}",
+            "Объединять части наладок:",
+            if self.report.merge_split_setups {
+                "да"
+            } else {
+                "нет"
+            }
+        )?;
+        writeln!(
+            f,
+            "  {:<WIDTH$}{}",
+            "Время наладки:",
+            self.report.time_policy.description()
+        )?;
+        for rule in &self.time_policies {
+            writeln!(
+                f,
+                "  {:<WIDTH$}{}",
+                format!("  {}:", rule.machine),
+                rule.policy.description()
+            )?;
+        }
+        writeln!(
+            f,
+            "  {:<WIDTH$}{}",
+            "Графики:",
+            if self.report.charts { "да" } else { "нет" }
+        )?;
+        writeln!(
+            f,
+            "  {:<WIDTH$}{}",
+            "Вложения:",
+            self.report
+                .attachments
+                .iter()
+                .map(|a| a.extension())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+
+        writeln!(
+            f,
+            "  {:<WIDTH$}{}",
+            "Шаблон:",
+            self.report
+                .template
+                .as_ref()
+                .map(|p| self.resolve_path(p).display().to_string())
+                .unwrap_or_else(|| "встроенный".to_string())
+        )?;
+
+        writeln!(
+            f,
+            "  {:<WIDTH$}{:?}, внутри станка {:?}",
+            "Порядок станков:", self.report.machine_order, self.report.part_order
+        )?;
+
+        writeln!(f, "\nПравила лимитов наладки (по приоритету):")?;
+        for rule in self.setup_limits.rules() {
+            writeln!(f, "  {}", rule)?;
+        }
+
+        writeln!(f, "\nРасписания:")?;
+        for schedule in &self.schedules {
+            writeln!(
+                f,
+                "  {:<WIDTH$}\"{}\", {:?} за {:?}, кому: {}",
+                format!("{}:", schedule.name),
+                schedule.cron,
+                schedule.kind,
+                schedule.period,
+                schedule.recipients(&self.smtp).join(", ")
+            )?;
+        }
+
+        if !self.recipient_groups.is_empty() {
+            writeln!(f, "\nГруппы получателей:")?;
+            for group in &self.recipient_groups {
+                writeln!(
+                    f,
+                    "  {:<WIDTH$}{}, кому: {}",
+                    format!("{}:", group.name),
+                    group
+                        .machines
+                        .iter()
+                        .map(|p| p.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    group.to.join(", ")
+                )?;
+            }
+            writeln!(
+                f,
+                "  {:<WIDTH$}{}",
+                "Остальные станки:",
+                self.report.fallback_to.join(", ")
+            )?;
+        }
+
+        writeln!(f, "\nОповещения о текущих наладках:")?;
+        if self.alerts.enabled {
+            writeln!(
+                f,
+                "  {:<WIDTH$}каждые {} мин., за последние {} ч.",
+                "Опрос:", self.alerts.poll_interval, self.alerts.lookback_hours
+            )?;
+            writeln!(
+                f,
+                "  {:<WIDTH$}{}",
+                "Кому:",
+                self.alerts.recipients(&self.smtp).join(", ")
+            )?;
+        } else {
+            writeln!(f, "  выключены")?;
+        }
+
+        writeln!(f, "\nНормы наладки по истории:")?;
+        if self.norms.enabled {
+            writeln!(
+                f,
+                "  {:<WIDTH$}{} дн., не меньше {} наладок",
+                "История:", self.norms.lookback_days, self.norms.min_samples
+            )?;
+            writeln!(
+                f,
+                "  {:<WIDTH$}{}",
+                "Вместо лимита:",
+                if self.norms.as_limit {
+                    format!("да, p90 + {}%", self.norms.margin_percent)
+                } else {
+                    "нет".to_string()
+                }
+            )?;
+        } else {
+            writeln!(f, "  выключены")?;
+        }
+
+        writeln!(
+            f,
+            "\n{:<WIDTH$}{}",
+            "Веб-интерфейс:",
+            if self.http.enabled {
+                format!(
+                    "http://{}, период запроса до {} дн.",
+                    self.http.listen, self.http.max_days
+                )
+            } else {
+                "выключен".to_string()
+            }
+        )?;
+
+        writeln!(
+            f,
+            "\n{:<WIDTH$}{}",
+            "Журнал отправок:",
+            crate::history::History::path(self).display()
         )?;
 
-        writeln!(f, "\nЛимиты наладки по оборудованию:")?;
-        for (equipment, limit) in &self.limits {
-            writeln!(f, "  {:<WIDTH$}{} мин", format!("{}:", equipment), limit)?;
+        writeln!(f, "\nОчередь писем:")?;
+        writeln!(
+            f,
+            "  {:<WIDTH$}{}",
+            "Папка:",
+            crate::outbox::Outbox::path(self).display()
+        )?;
+        writeln!(
+            f,
+            "  {:<WIDTH$}от {} до {} мин., {}",
+            "Повтор отправки:",
+            self.outbox.retry_interval,
+            self.outbox.max_retry_interval,
+            match self.outbox.max_age_hours {
+                Some(hours) => format!("в течение {hours} ч."),
+                None => "без ограничения срока".to_string(),
+            }
+        )?;
+
+        writeln!(f, "\nСмены:")?;
+        for shift in self.shifts.shifts() {
+            let breaks = shift
+                .breaks
+                .iter()
+                .map(|b| {
+                    format!(
+                        "{} ({} мин)",
+                        b.start.format("%H:%M"),
+                        b.duration.num_minutes()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(
+                f,
+                "  {:<WIDTH$}{}-{}, перерывы: {}",
+                format!("{}:", shift.name),
+                shift.start.format("%H:%M"),
+                shift.end.format("%H:%M"),
+                breaks
+            )?;
         }
 
         writeln!(f, "\nОбщие настройки:")?;
@@ -131,11 +1111,27 @@ impl fmt::Display for Settings {
             "  {:<25}{}",
             "Уровень логирования:", self.general.log_level
         )?;
-        write!(
+        writeln!(
             f,
             "  {:<25}{}",
             "Задержка отправки, сек:", self.general.send_delay
         )?;
+        let retry = &self.general.retry;
+        write!(
+            f,
+            "  {:<25}{} попыток, пауза от {} до {} сек. (x{}, ±{}%), не дольше {}",
+            "Повторные попытки:",
+            retry.max_attempts,
+            retry.initial_delay,
+            retry.max_delay,
+            retry.multiplier,
+            (retry.jitter * 100.0).round(),
+            if retry.deadline > 0 {
+                format!("{} сек.", retry.deadline)
+            } else {
+                "без ограничения".to_string()
+            }
+        )?;
 
         Ok(())
     }
@@ -0,0 +1,226 @@
+use crate::config::{ColumnMapping, Settings};
+use crate::models::{DateRange, PartData};
+use crate::source::SetupSource;
+use crate::utils::PermanentError;
+use async_trait::async_trait;
+use chrono::{NaiveDate, NaiveDateTime};
+use csv::StringRecord;
+use eyre::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Наладки из CSV-выгрузки: одного файла или всех `*.csv` в папке.
+/// Файлы читаются заново при каждом запросе.
+pub struct CsvSource {
+    path: PathBuf,
+    delimiter: u8,
+    datetime_format: String,
+    columns: ColumnMapping,
+}
+
+/// Строка выгрузки со сменной датой, нужной для отбора по периоду.
+struct CsvRow {
+    shift_date: NaiveDate,
+    /// `None`, если наладка еще не завершена
+    end_setup_time: Option<NaiveDateTime>,
+    part: PartData,
+}
+
+impl CsvSource {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let source = &settings.source;
+        let path = source
+            .path
+            .clone()
+            .ok_or_else(|| PermanentError("Не задан source.path".to_string()))?;
+        let delimiter = u8::try_from(source.delimiter).map_err(|_| {
+            PermanentError("source.delimiter должен быть ASCII-символом".to_string())
+        })?;
+        Ok(Self {
+            path,
+            delimiter,
+            datetime_format: source.datetime_format.clone(),
+            columns: source.columns.clone(),
+        })
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        if self.path.is_file() {
+            return Ok(vec![self.path.clone()]);
+        }
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.path)
+            .wrap_err_with(|| format!("Не удалось открыть {}", self.path.display()))?
+        {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"))
+            {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn read_rows(&self) -> Result<Vec<CsvRow>> {
+        let mut rows = Vec::new();
+        for file in self.files()? {
+            self.read_file(&file, &mut rows)
+                .wrap_err_with(|| format!("Ошибка чтения {}", file.display()))?;
+        }
+        Ok(rows)
+    }
+
+    fn read_file(&self, file: &Path, rows: &mut Vec<CsvRow>) -> Result<()> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .trim(csv::Trim::All)
+            .from_path(file)?;
+        let headers = reader.headers()?.clone();
+        let mut index = HashMap::new();
+        for column in self.columns.all() {
+            let position = headers
+                .iter()
+                .position(|h| h == column.name)
+                .ok_or_else(|| {
+                    PermanentError(format!(
+                        "Нет столбца {} (source.columns.{})",
+                        column.name, column.key
+                    ))
+                })?;
+            index.insert(column.field, position);
+        }
+
+        let (mut total, mut rejected) = (0, 0);
+        let mut first_error = None;
+        for (line, record) in reader.records().enumerate() {
+            total += 1;
+            match record
+                .map_err(eyre::Report::from)
+                .and_then(|record| self.parse_row(&record, &index))
+            {
+                Ok(row) => rows.push(row),
+                Err(e) => {
+                    // Первая строка файла - заголовок
+                    debug!("Строка {} пропущена: {:?}", line + 2, e);
+                    rejected += 1;
+                    first_error.get_or_insert_with(|| format!("строка {}: {:#}", line + 2, e));
+                }
+            }
+        }
+        let first_error = first_error.unwrap_or_default();
+        // Неверный формат даты или разделитель отбрасывают все строки: это ошибка
+        // настроек, а не выгрузка без наладок
+        if total > 0 && rejected == total {
+            return Err(PermanentError(format!(
+                "Ни одна из {total} строк не разобрана, проверьте source.datetime_format \
+                 и source.delimiter ({first_error})"
+            ))
+            .into());
+        }
+        if rejected > 0 {
+            warn!(
+                "{}: пропущено строк {} из {}, первая ошибка - {}",
+                file.display(),
+                rejected,
+                total,
+                first_error
+            );
+        }
+        Ok(())
+    }
+
+    fn parse_row(
+        &self,
+        record: &StringRecord,
+        index: &HashMap<&'static str, usize>,
+    ) -> Result<CsvRow> {
+        let field = |column: &str| record.get(index[column]).unwrap_or_default();
+        let datetime = |column: &str| {
+            NaiveDateTime::parse_from_str(field(column), &self.datetime_format)
+                .wrap_err_with(|| format!("Неверное значение {}: {}", column, field(column)))
+        };
+        let end_setup_time = match field("StartMachiningTime") {
+            "" => None,
+            _ => Some(datetime("StartMachiningTime")?),
+        };
+        let start_setup_time = datetime("StartSetupTime")?;
+        let shift_date = NaiveDate::parse_from_str(field("ShiftDate"), "%Y-%m-%d")
+            .or_else(|_| NaiveDate::parse_from_str(field("ShiftDate"), "%d.%m.%Y"))
+            .or_else(|_| datetime("ShiftDate").map(|dt| dt.date()))?;
+        let downtimes = match field("SetupDowntimes") {
+            "" => 0.0,
+            value => value
+                .replace(',', ".")
+                .parse()
+                .wrap_err_with(|| format!("Неверное значение SetupDowntimes: {value}"))?,
+        };
+        Ok(CsvRow {
+            shift_date,
+            end_setup_time,
+            part: PartData {
+                part_name: field("PartName").to_string(),
+                setup: field("Setup")
+                    .parse()
+                    .wrap_err_with(|| format!("Неверное значение Setup: {}", field("Setup")))?,
+                order: field("Order").to_string(),
+                machine: field("Machine").to_string(),
+                operator: field("Operator").to_string(),
+                start_setup_time,
+                end_setup_time: end_setup_time.unwrap_or(start_setup_time),
+                operators_comment: field("OperatorComment").to_string(),
+                downtimes,
+                gaps: Vec::new(),
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl SetupSource for CsvSource {
+    async fn ping(&mut self) -> Result<()> {
+        let files = self.files()?;
+        if files.is_empty() {
+            eyre::bail!("В {} нет CSV-файлов", self.path.display());
+        }
+        Ok(())
+    }
+
+    async fn reconnect(&mut self, _settings: &Settings) -> Result<()> {
+        Ok(())
+    }
+
+    async fn fetch_setups(&mut self, period: &DateRange) -> Result<Vec<PartData>> {
+        let mut parts: Vec<PartData> = self
+            .read_rows()?
+            .into_iter()
+            .filter(|row| row.end_setup_time.is_some())
+            .filter(|row| (period.from..=period.to).contains(&row.shift_date))
+            .map(|row| row.part)
+            .collect();
+        parts.sort_by_key(|part| std::cmp::Reverse(part.start_setup_time));
+        Ok(parts)
+    }
+
+    async fn fetch_running_setups(
+        &mut self,
+        since: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<Vec<PartData>> {
+        let mut parts: Vec<PartData> = self
+            .read_rows()?
+            .into_iter()
+            .filter(|row| row.end_setup_time.is_none() && row.part.start_setup_time >= since)
+            .map(|row| PartData {
+                end_setup_time: now,
+                ..row.part
+            })
+            .collect();
+        parts.sort_by_key(|part| part.start_setup_time);
+        Ok(parts)
+    }
+}
@@ -1,89 +1,212 @@
-use crate::{config::Settings, models::PartData};
+use crate::{
+    calendar::ShiftCalendar,
+    config::{DbEncryption, Settings, SourceSettings},
+    models::{DateRange, PartData},
+    source::{select_query, SetupSource, COMPLETED_FIELDS, RUNNING_FIELDS},
+    utils::PermanentError,
+};
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
 use eyre::{Context, Result};
-use tiberius::{Client, Config};
+use tiberius::{AuthMethod, Client, Config, EncryptionLevel, Query, SqlBrowser};
 use tokio::net::TcpStream;
-use tokio_util::compat::TokioAsyncWriteCompatExt;
+use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
 use tracing::debug;
 
 pub struct Database {
-    pub client: Option<Client<tokio_util::compat::Compat<TcpStream>>>,
+    pub client: Option<Client<Compat<TcpStream>>>,
+    source: SourceSettings,
+    /// График смен для вывода наладок в журнал
+    calendar: ShiftCalendar,
 }
 
 impl Database {
     pub async fn new(settings: &Settings) -> Result<Self> {
+        let mut db = Self {
+            client: Some(Self::connect(settings).await?),
+            source: settings.source.clone(),
+            calendar: settings.shifts.clone(),
+        };
+        db.validate_columns().await?;
+        Ok(db)
+    }
+
+    /// Проверяет по `INFORMATION_SCHEMA.COLUMNS`, что в `source.table` есть все столбцы
+    /// из `source.columns` и их типы читаются как поля наладки.
+    pub async fn validate_columns(&mut self) -> Result<()> {
+        let unquote = |name: &str| name.trim().trim_matches(['[', ']']).to_string();
+        let (schema, table) = match self.source.table.rsplit_once('.') {
+            Some((schema, table)) => (Some(unquote(schema)), unquote(table)),
+            None => (None, unquote(&self.source.table)),
+        };
+
+        let mut query = Query::new(
+            "SELECT COLUMN_NAME, DATA_TYPE FROM INFORMATION_SCHEMA.COLUMNS
+             WHERE TABLE_NAME = @P1 AND TABLE_SCHEMA = COALESCE(@P2, SCHEMA_NAME())",
+        );
+        query.bind(table);
+        query.bind(schema);
+        let rows = query
+            .query(self.client()?)
+            .await
+            .wrap_err("Ошибка чтения INFORMATION_SCHEMA.COLUMNS")?
+            .into_first_result()
+            .await?;
+        let found: Vec<(String, String)> = rows
+            .iter()
+            .filter_map(|row| {
+                let name: &str = row.get(0)?;
+                let data_type: &str = row.get(1)?;
+                Some((name.to_string(), data_type.to_lowercase()))
+            })
+            .collect();
+        if found.is_empty() {
+            return Err(PermanentError(format!(
+                "Таблица {} (source.table) не найдена или недоступна",
+                self.source.table
+            ))
+            .into());
+        }
+
+        let mut problems = Vec::new();
+        for column in self.source.columns.all() {
+            let expected = expected_types(column.field);
+            match found
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(column.name))
+            {
+                None => problems.push(format!(
+                    "нет столбца {} (source.columns.{})",
+                    column.name, column.key
+                )),
+                Some((_, data_type)) if !expected.contains(&data_type.as_str()) => {
+                    problems.push(format!(
+                        "столбец {} (source.columns.{}) имеет тип {}, ожидается {}",
+                        column.name,
+                        column.key,
+                        data_type,
+                        expected.join(" или ")
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        if !problems.is_empty() {
+            return Err(PermanentError(format!(
+                "Таблица {} не подходит: {}",
+                self.source.table,
+                problems.join("; ")
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn connect(settings: &Settings) -> Result<Client<Compat<TcpStream>>> {
         let config = Self::create_config(settings)?;
-        let tcp = TcpStream::connect(config.get_addr()).await?;
+        let tcp = TcpStream::connect_named(&config).await?;
         tcp.set_nodelay(true)?;
-        let client = Client::connect(config, tcp.compat_write()).await?;
-
-        Ok(Self {
-            client: Some(client),
-        })
+        Client::connect(config, tcp.compat_write())
+            .await
+            .map_err(|e| match e {
+                tiberius::error::Error::Tls(_) => eyre::Report::new(e).wrap_err(
+                    "Не удалось проверить сертификат SQL Server. Укажите сертификат \
+                     в database.ca_file, добавьте его в системное хранилище \
+                     или, если сервер доверенный, включите database.trust_cert",
+                ),
+                e => e.into(),
+            })
     }
 
     fn create_config(settings: &Settings) -> Result<Config> {
-        let config_str = format!(
-            "Data Source={};Initial Catalog={};TrustServerCertificate=True;User ID={};Password={};",
-            settings.database.host,
-            settings.database.database,
-            settings.database.username,
-            settings.database.password
-        );
-        let mut config = Config::from_ado_string(&config_str)?;
-        config.trust_cert();
+        let db = &settings.database;
+        let address = db.address().map_err(PermanentError)?;
+        let mut config = Config::new();
+        config.host(&address.host);
+        if let Some(port) = address.port {
+            config.port(port);
+        } else if let Some(instance) = &address.instance {
+            config.instance_name(instance);
+        }
+        config.database(&db.database);
+        config.authentication(AuthMethod::sql_server(&db.username, &db.password));
+        config.encryption(match db.encryption {
+            DbEncryption::Off => EncryptionLevel::Off,
+            DbEncryption::On => EncryptionLevel::On,
+            DbEncryption::Required => EncryptionLevel::Required,
+        });
+        if let Some(ca_file) = &db.ca_file {
+            config.trust_cert_ca(ca_file.display());
+        } else if db.trust_cert {
+            config.trust_cert();
+        }
         Ok(config)
     }
 
-    pub async fn reconnect(&mut self, settings: &Settings) -> Result<()> {
-        let config = Self::create_config(settings)?;
-        let tcp = TcpStream::connect(config.get_addr()).await?;
-        tcp.set_nodelay(true)?;
-        let client = Client::connect(config, tcp.compat_write()).await?;
+    fn client(&mut self) -> Result<&mut Client<Compat<TcpStream>>> {
+        self.client
+            .as_mut()
+            .ok_or_else(|| eyre::eyre!("Нет активного подключения к базе данных"))
+    }
+}
 
-        self.client = Some(client);
+#[async_trait]
+impl SetupSource for Database {
+    async fn reconnect(&mut self, settings: &Settings) -> Result<()> {
+        self.client = Some(Self::connect(settings).await?);
+        self.calendar = settings.shifts.clone();
+        let previous = std::mem::replace(&mut self.source, settings.source.clone());
+        if previous.table != self.source.table || previous.columns != self.source.columns {
+            // При ошибке остается прежнее сопоставление, и проверка повторится
+            // при следующем переподключении
+            if let Err(e) = self.validate_columns().await {
+                self.source = previous;
+                return Err(e);
+            }
+        }
         Ok(())
     }
 
-    pub async fn fetch_report_data(&mut self, settings: &Settings) -> Result<Vec<PartData>> {
-        const QUERY: &str = r#"
-            SELECT
-                PartName,
-                Setup,
-                [Order],
-                Machine,
-                Operator,
-                StartSetupTime,
-                StartMachiningTime,
-                SetupDowntimes,
-                OperatorComment
-            FROM
-                parts
-            WHERE
-                ShiftDate = CONVERT(DATE, DATEADD(day, -1, GETDATE()))
-            ORDER BY
-                StartSetupTime DESC;
-        "#;
-
-        let client = self
-            .client
-            .as_mut()
-            .ok_or_else(|| eyre::eyre!("Нет активного подключения к базе данных"))?;
+    async fn ping(&mut self) -> Result<()> {
+        let client = self.client()?;
+        client
+            .simple_query("SELECT 1")
+            .await
+            .wrap_err("Ошибка выполнения запроса")?
+            .into_results()
+            .await
+            .wrap_err("Ошибка получения результатов")?;
+        Ok(())
+    }
 
-        let results = client
-            .simple_query(QUERY)
+    async fn fetch_setups(&mut self, period: &DateRange) -> Result<Vec<PartData>> {
+        let sql = select_query(
+            &self.source,
+            &COMPLETED_FIELDS,
+            "{ShiftDate} BETWEEN @P1 AND @P2",
+            "{StartSetupTime} DESC",
+        );
+        let client = self.client()?;
+
+        let mut query = Query::new(sql);
+        query.bind(period.from);
+        query.bind(period.to);
+
+        let results = query
+            .query(client)
             .await
             .wrap_err("Ошибка выполнения запроса")?
             .into_results()
             .await
             .wrap_err("Ошибка получения результатов")?;
 
-        let mut filtered_data: Vec<PartData> = Vec::new();
+        let mut parts: Vec<PartData> = Vec::new();
 
         for row in results.into_iter().flatten() {
             let part_data = match PartData::from_sql_row(&row) {
                 Ok(data) => {
                     debug!("Обработка данных для станка: {}", data.machine);
-                    debug!("{}", data);
+                    debug!("{}", data.display(&self.calendar));
                     data
                 }
                 Err(e) => {
@@ -92,32 +215,54 @@ impl Database {
                 }
             };
 
-            // let start_setup_time = if let Some(prev_part) = filtered_data.last() {
-            //     if prev_part.part_name == part_data.part_name
-            //         && prev_part.setup == part_data.setup
-            //         && prev_part.order == part_data.order
-            //     {
-            //         prev_part.start_setup_time
-            //     } else {
-            //         part_data.start_setup_time
-            //     }
-            // } else {
-            //     part_data.start_setup_time
-            // };
-
-            let setup_duration = part_data.end_setup_time - part_data.start_setup_time; // тут было start_setup_time
-            let actual_minutes = (setup_duration - part_data.breaks_between(true)).num_minutes();
-            let limit = settings.get_setup_limit(&part_data.machine);
-
-            if actual_minutes > limit.into() {
-                debug!(
-                    "Превышение лимита наладки:\nСтанок: {}\n{}\nЛимит: {}\nФактическое время: {}",
-                    part_data.machine, part_data.part_name, limit, actual_minutes
-                );
-                filtered_data.push(part_data);
+            parts.push(part_data);
+        }
+
+        Ok(parts)
+    }
+
+    async fn fetch_running_setups(
+        &mut self,
+        since: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<Vec<PartData>> {
+        let sql = select_query(
+            &self.source,
+            &RUNNING_FIELDS,
+            "{StartSetupTime} >= @P1 AND {StartMachiningTime} IS NULL",
+            "{StartSetupTime}",
+        );
+        let client = self.client()?;
+
+        let mut query = Query::new(sql);
+        query.bind(since);
+
+        let results = query
+            .query(client)
+            .await
+            .wrap_err("Ошибка выполнения запроса")?
+            .into_results()
+            .await
+            .wrap_err("Ошибка получения результатов")?;
+
+        let mut running = Vec::new();
+        for row in results.into_iter().flatten() {
+            match PartData::running_from_sql_row(&row, now) {
+                Ok(data) => running.push(data),
+                Err(e) => debug!("Ошибка при разборе строки данных: {:?}", e),
             }
         }
+        Ok(running)
+    }
+}
 
-        Ok(filtered_data)
+/// Типы SQL Server, которые читаются в поле наладки `field`.
+pub fn expected_types(field: &str) -> &'static [&'static str] {
+    match field {
+        "Setup" => &["int"],
+        "SetupDowntimes" => &["float"],
+        "StartSetupTime" | "StartMachiningTime" => &["datetime", "datetime2", "smalldatetime"],
+        "ShiftDate" => &["date", "datetime", "datetime2", "smalldatetime"],
+        _ => &["nvarchar", "varchar", "nchar", "char"],
     }
 }
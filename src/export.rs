@@ -0,0 +1,139 @@
+use crate::config::{AttachmentFormat, Settings};
+use crate::mailer::Attachment;
+use crate::models::{PartData, ReportRow};
+use eyre::{Context, Result};
+use rust_xlsxwriter::{Format, Workbook};
+
+const HEADERS: [&str; 16] = [
+    "Станок",
+    "Деталь",
+    "Установка",
+    "М/Л",
+    "Оператор",
+    "Смена",
+    "Начало наладки",
+    "Окончание наладки",
+    "Наладка, мин",
+    "Чистое время, мин",
+    "Расчет времени",
+    "Перерывы, мин",
+    "Лимит, мин",
+    "Превышение, мин",
+    "Простои, мин",
+    "Комментарий",
+];
+
+const DATETIME_FORMAT: &str = "%d.%m.%Y %H:%M:%S";
+
+/// Вложения с данными отчета в форматах из `report.attachments`.
+pub fn build_attachments(data: &[PartData], settings: &Settings) -> Result<Vec<Attachment>> {
+    if settings.report.attachments.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rows: Vec<ReportRow> = data.iter().map(|p| ReportRow::new(p, settings)).collect();
+    let file_stem = file_stem(&rows);
+
+    settings
+        .report
+        .attachments
+        .iter()
+        .map(|format| {
+            let (content_type, data) = match format {
+                AttachmentFormat::Csv => ("text/csv", to_csv(&rows)?),
+                AttachmentFormat::Xlsx => (
+                    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                    to_xlsx(&rows)?,
+                ),
+            };
+            Ok(Attachment {
+                filename: format!("{}.{}", file_stem, format.extension()),
+                content_type,
+                data,
+                content_id: None,
+            })
+        })
+        .collect()
+}
+
+fn file_stem(rows: &[ReportRow]) -> String {
+    let first = rows.iter().map(|r| r.start_setup_time.date()).min();
+    let last = rows.iter().map(|r| r.start_setup_time.date()).max();
+    match (first, last) {
+        (Some(first), Some(last)) if first != last => format!(
+            "long_setups_{}_{}",
+            first.format("%Y-%m-%d"),
+            last.format("%Y-%m-%d")
+        ),
+        (Some(day), _) => format!("long_setups_{}", day.format("%Y-%m-%d")),
+        _ => "long_setups".to_string(),
+    }
+}
+
+/// CSV в UTF-8 с BOM и разделителем `;`, который Excel с русской локалью
+/// открывает по столбцам без мастера импорта.
+pub fn to_csv(rows: &[ReportRow]) -> Result<Vec<u8>> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b';')
+        .from_writer(b"\xEF\xBB\xBF".to_vec());
+    writer.write_record(HEADERS)?;
+    for row in rows {
+        writer.write_record([
+            row.machine.clone(),
+            row.part_name.clone(),
+            row.setup.to_string(),
+            row.order.clone(),
+            row.operator.clone(),
+            row.shift.clone(),
+            row.start_setup_time.format(DATETIME_FORMAT).to_string(),
+            row.end_setup_time.format(DATETIME_FORMAT).to_string(),
+            row.gross_minutes.to_string(),
+            row.setup_minutes.to_string(),
+            row.time_policy.clone(),
+            row.breaks_minutes.to_string(),
+            row.limit.to_string(),
+            row.overrun.to_string(),
+            row.downtimes.to_string().replace('.', ","),
+            row.operators_comment.clone(),
+        ])?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| eyre::eyre!("Ошибка формирования CSV: {}", e))
+}
+
+pub fn to_xlsx(rows: &[ReportRow]) -> Result<Vec<u8>> {
+    let mut workbook = Workbook::new();
+    let header_format = Format::new().set_bold();
+    let datetime_format = Format::new().set_num_format("dd.mm.yyyy hh:mm:ss");
+
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Длительные наладки")?;
+    for (col, header) in HEADERS.iter().enumerate() {
+        sheet.write_string_with_format(0, col as u16, *header, &header_format)?;
+    }
+    for (i, row) in rows.iter().enumerate() {
+        let r = i as u32 + 1;
+        sheet.write_string(r, 0, &row.machine)?;
+        sheet.write_string(r, 1, &row.part_name)?;
+        sheet.write_number(r, 2, row.setup)?;
+        sheet.write_string(r, 3, &row.order)?;
+        sheet.write_string(r, 4, &row.operator)?;
+        sheet.write_string(r, 5, &row.shift)?;
+        sheet.write_datetime_with_format(r, 6, row.start_setup_time, &datetime_format)?;
+        sheet.write_datetime_with_format(r, 7, row.end_setup_time, &datetime_format)?;
+        sheet.write_number(r, 8, row.gross_minutes as f64)?;
+        sheet.write_number(r, 9, row.setup_minutes as f64)?;
+        sheet.write_string(r, 10, &row.time_policy)?;
+        sheet.write_number(r, 11, row.breaks_minutes as f64)?;
+        sheet.write_number(r, 12, row.limit as f64)?;
+        sheet.write_number(r, 13, row.overrun as f64)?;
+        sheet.write_number(r, 14, row.downtimes)?;
+        sheet.write_string(r, 15, &row.operators_comment)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    sheet.autofit();
+
+    workbook
+        .save_to_buffer()
+        .wrap_err("Ошибка формирования XLSX")
+}
@@ -0,0 +1,310 @@
+use crate::config::Settings;
+use crate::models::{DateRange, ReportRow, SetupKey};
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use eyre::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::env;
+use std::path::{Path, PathBuf};
+
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS deliveries (
+        id          INTEGER PRIMARY KEY,
+        name        TEXT NOT NULL,
+        period_from TEXT NOT NULL,
+        period_to   TEXT NOT NULL,
+        subject     TEXT NOT NULL,
+        recipients  TEXT NOT NULL,
+        status      TEXT NOT NULL,
+        error       TEXT,
+        created_at  TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS deliveries_period ON deliveries (name, period_from, period_to);
+
+    CREATE TABLE IF NOT EXISTS setups (
+        id                INTEGER PRIMARY KEY,
+        delivery_id       INTEGER NOT NULL REFERENCES deliveries (id) ON DELETE CASCADE,
+        machine           TEXT NOT NULL,
+        part_name         TEXT NOT NULL,
+        setup             INTEGER NOT NULL,
+        order_no          TEXT NOT NULL,
+        operator          TEXT NOT NULL,
+        shift             TEXT NOT NULL,
+        start_setup_time  TEXT NOT NULL,
+        end_setup_time    TEXT NOT NULL,
+        setup_minutes     INTEGER NOT NULL,
+        breaks_minutes    INTEGER NOT NULL,
+        limit_minutes     INTEGER NOT NULL,
+        overrun           INTEGER NOT NULL,
+        downtimes         REAL NOT NULL,
+        operators_comment TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS setups_start ON setups (start_setup_time);
+
+    CREATE TABLE IF NOT EXISTS alerts (
+        machine          TEXT NOT NULL,
+        order_no         TEXT NOT NULL,
+        part_name        TEXT NOT NULL,
+        setup            INTEGER NOT NULL,
+        start_setup_time TEXT NOT NULL,
+        alerted_at       TEXT NOT NULL,
+        PRIMARY KEY (machine, order_no, part_name, setup, start_setup_time)
+    );
+"#;
+
+/// Итог отправки отчета.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Sent,
+    /// Длительных наладок не было, письмо не отправлялось
+    Empty,
+    /// Письмо ждет повторной отправки в очереди
+    Queued,
+    Failed,
+    /// Письмо удалено из очереди командой `lsr outbox purge` и не досылается
+    Purged,
+}
+
+/// Запись журнала об одной отправке.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub id: i64,
+    pub name: String,
+    pub period: DateRange,
+    pub subject: String,
+    pub recipients: Vec<String>,
+    pub status: DeliveryStatus,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub setups: usize,
+}
+
+/// Локальный журнал отправленных отчетов и попавших в них длительных наладок.
+pub struct History {
+    conn: Connection,
+}
+
+impl DeliveryStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Empty => "empty",
+            DeliveryStatus::Queued => "queued",
+            DeliveryStatus::Failed => "failed",
+            DeliveryStatus::Purged => "purged",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "sent" => DeliveryStatus::Sent,
+            "empty" => DeliveryStatus::Empty,
+            "queued" => DeliveryStatus::Queued,
+            "purged" => DeliveryStatus::Purged,
+            _ => DeliveryStatus::Failed,
+        }
+    }
+}
+
+impl History {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)
+            .wrap_err_with(|| format!("Не удалось открыть журнал {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        conn.execute_batch(SCHEMA)
+            .wrap_err("Ошибка создания таблиц журнала")?;
+        Ok(Self { conn })
+    }
+
+    /// Путь из `history.path` или `history.sqlite` рядом с исполняемым файлом.
+    pub fn path(settings: &Settings) -> PathBuf {
+        match &settings.history.path {
+            Some(path) => settings.resolve_path(path),
+            None => env::current_exe()
+                .ok()
+                .and_then(|path| path.parent().map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("history.sqlite"),
+        }
+    }
+
+    /// Сохраняет итог отправки вместе с наладками, попавшими в отчет.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &mut self,
+        name: &str,
+        period: &DateRange,
+        subject: &str,
+        recipients: &[String],
+        rows: &[ReportRow],
+        status: DeliveryStatus,
+        error: Option<&str>,
+    ) -> Result<i64> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO deliveries
+                (name, period_from, period_to, subject, recipients, status, error, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                name,
+                period.from,
+                period.to,
+                subject,
+                recipients.join(", "),
+                status.as_str(),
+                error,
+                Local::now().naive_local(),
+            ],
+        )?;
+        let delivery_id = tx.last_insert_rowid();
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO setups
+                    (delivery_id, machine, part_name, setup, order_no, operator, shift,
+                     start_setup_time, end_setup_time, setup_minutes, breaks_minutes,
+                     limit_minutes, overrun, downtimes, operators_comment)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            )?;
+            for row in rows {
+                insert.execute(params![
+                    delivery_id,
+                    row.machine,
+                    row.part_name,
+                    row.setup,
+                    row.order,
+                    row.operator,
+                    row.shift,
+                    row.start_setup_time,
+                    row.end_setup_time,
+                    row.setup_minutes,
+                    row.breaks_minutes,
+                    row.limit,
+                    row.overrun,
+                    row.downtimes,
+                    row.operators_comment,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(delivery_id)
+    }
+
+    /// Обновляет итог отправки, например после доставки письма из очереди.
+    pub fn set_status(&self, id: i64, status: DeliveryStatus, error: Option<&str>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE deliveries SET status = ?2, error = ?3 WHERE id = ?1",
+            params![id, status.as_str(), error],
+        )?;
+        Ok(())
+    }
+
+    /// Был ли отчет `name` за `period` уже доставлен, поставлен в очередь,
+    /// удален из нее вручную или доставлять было нечего.
+    pub fn was_delivered(&self, name: &str, period: &DateRange) -> Result<bool> {
+        let found = self
+            .conn
+            .query_row(
+                "SELECT 1 FROM deliveries
+                 WHERE name = ?1 AND period_from = ?2 AND period_to = ?3
+                   AND status IN ('sent', 'empty', 'queued', 'purged')
+                 LIMIT 1",
+                params![name, period.from, period.to],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Начало самого раннего периода в журнале по отчету `name`, включая неудачные отправки.
+    pub fn first_period(&self, name: &str) -> Result<Option<NaiveDate>> {
+        self.conn
+            .query_row(
+                "SELECT MIN(period_from) FROM deliveries WHERE name = ?1",
+                [name],
+                |row| row.get(0),
+            )
+            .map_err(Into::into)
+    }
+
+    /// Отмечает, что по наладке отправлено оповещение о превышении лимита.
+    pub fn record_alert(&self, key: &SetupKey) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO alerts
+                (machine, order_no, part_name, setup, start_setup_time, alerted_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                key.machine,
+                key.order,
+                key.part_name,
+                key.setup,
+                key.start_setup_time,
+                Local::now().naive_local(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Наладки, начатые не раньше `since`, по которым уже отправлено оповещение.
+    pub fn alerted_setups(&self, since: NaiveDateTime) -> Result<Vec<SetupKey>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT machine, order_no, part_name, setup, start_setup_time
+             FROM alerts
+             WHERE start_setup_time >= ?1",
+        )?;
+        let rows = stmt.query_map([since], |row| {
+            Ok(SetupKey {
+                machine: row.get(0)?,
+                order: row.get(1)?,
+                part_name: row.get(2)?,
+                setup: row.get(3)?,
+                start_setup_time: row.get(4)?,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<_>>().map_err(Into::into)
+    }
+
+    /// Последние записи журнала, новые первыми.
+    pub fn deliveries(&self, limit: usize) -> Result<Vec<Delivery>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT d.id, d.name, d.period_from, d.period_to, d.subject, d.recipients,
+                    d.status, d.error, d.created_at,
+                    (SELECT COUNT(*) FROM setups s WHERE s.delivery_id = d.id)
+             FROM deliveries d
+             ORDER BY d.id DESC
+             LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            let from: NaiveDate = row.get(2)?;
+            let to: NaiveDate = row.get(3)?;
+            let recipients: String = row.get(5)?;
+            let status: String = row.get(6)?;
+            Ok(Delivery {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                period: DateRange { from, to },
+                subject: row.get(4)?,
+                recipients: recipients
+                    .split(", ")
+                    .filter(|r| !r.is_empty())
+                    .map(String::from)
+                    .collect(),
+                status: DeliveryStatus::parse(&status),
+                error: row.get(7)?,
+                created_at: row.get(8)?,
+                setups: row.get::<_, i64>(9)? as usize,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<_>>().map_err(Into::into)
+    }
+}
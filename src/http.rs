@@ -0,0 +1,227 @@
+use crate::config::Settings;
+use crate::init::Services;
+use crate::models::{DateRange, PartData};
+use crate::norms::with_norms;
+use crate::pattern::NamePattern;
+use crate::reports::generate_html_report;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{Local, NaiveDate};
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex as TokioMutex;
+use tokio::time::{sleep, Duration as TokioDuration};
+use tracing::{error, info, warn};
+
+const DASHBOARD: &str = include_str!("../templates/dashboard.html");
+/// Как часто веб-интерфейс перечитывает настройки
+const SETTINGS_RELOAD: TokioDuration = TokioDuration::from_secs(60);
+
+/// Параметры запроса наладок. Даты - сменные, по умолчанию вчерашний день;
+/// если задана только одна граница, период состоит из одного дня.
+#[derive(Debug, Default, Deserialize)]
+pub struct SetupsQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    /// Станок: точное имя, маска с `*` и `?` или `/регулярное выражение/`
+    pub machine: Option<String>,
+    /// Только наладки дольше лимита
+    #[serde(default)]
+    pub long: bool,
+}
+
+/// Наладка в ответе API: данные из источника и рассчитанные по настройкам показатели.
+#[derive(Debug, Serialize)]
+pub struct SetupJson {
+    #[serde(flatten)]
+    pub part: PartData,
+    /// Чистое время наладки, с ним сравнивается лимит
+    pub setup_minutes: i64,
+    pub limit: i64,
+    pub overrun: i64,
+}
+
+/// Ошибка запроса: текст уходит клиенту с кодом ответа.
+#[derive(Debug)]
+pub struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+impl From<eyre::Report> for ApiError {
+    /// Подробности ошибки остаются в журнале, клиенту уходит только общий текст.
+    fn from(e: eyre::Report) -> Self {
+        error!("Ошибка запроса к веб-интерфейсу: {:?}", e);
+        Self(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Внутренняя ошибка, подробности в журнале приложения".to_string(),
+        )
+    }
+}
+
+impl SetupsQuery {
+    /// Период запроса, не длиннее `max_days` дней.
+    pub fn period(&self, today: NaiveDate, max_days: i64) -> Result<DateRange, ApiError> {
+        let yesterday = today.pred_opt().unwrap_or(today);
+        let (from, to) = match (self.from, self.to) {
+            (None, None) => (yesterday, yesterday),
+            (Some(day), None) | (None, Some(day)) => (day, day),
+            (Some(from), Some(to)) => (from, to),
+        };
+        let period = DateRange::new(from, to)
+            .map_err(|e| ApiError(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if period.days() > max_days {
+            return Err(ApiError(
+                StatusCode::BAD_REQUEST,
+                format!("Период {period} длиннее {max_days} дн., выберите более короткий"),
+            ));
+        }
+        Ok(period)
+    }
+
+    pub fn machine(&self) -> Result<Option<NamePattern>, ApiError> {
+        self.machine
+            .as_deref()
+            .map(str::trim)
+            .filter(|machine| !machine.is_empty())
+            .map(NamePattern::new)
+            .transpose()
+            .map_err(|e| ApiError(StatusCode::BAD_REQUEST, e.to_string()))
+    }
+}
+
+/// Наладки `parts`, отобранные по станку и превышению лимита, с рассчитанным временем.
+pub fn setup_rows(
+    parts: Vec<PartData>,
+    settings: &Settings,
+    machine: Option<&NamePattern>,
+    long_only: bool,
+) -> Vec<SetupJson> {
+    parts
+        .into_iter()
+        .filter(|part| machine.is_none_or(|pattern| pattern.is_match(&part.machine)))
+        .map(|part| {
+            let setup_minutes = part.setup_minutes(settings);
+            let limit = settings.get_setup_limit(&part.machine, &part.part_name, part.setup);
+            SetupJson {
+                setup_minutes,
+                limit,
+                overrun: (setup_minutes - limit).max(0),
+                part,
+            }
+        })
+        .filter(|row| !long_only || row.overrun > 0)
+        .collect()
+}
+
+struct AppState {
+    services: Services,
+    settings: TokioMutex<Settings>,
+}
+
+impl AppState {
+    /// Последние прочитанные настройки, без норм.
+    async fn settings(&self) -> Settings {
+        self.settings.lock().await.clone()
+    }
+
+    /// Перечитывает настройки раз в `SETTINGS_RELOAD`. Файл читается без блокировки,
+    /// поэтому запросы ее почти не ждут.
+    async fn reload_settings(self: Arc<Self>) {
+        loop {
+            sleep(SETTINGS_RELOAD).await;
+            let mut settings = self.settings().await;
+            match settings.update() {
+                Ok(_) => *self.settings.lock().await = settings,
+                Err(e) => warn!(
+                    "Не удалось обновить параметры приложения: {}\nИспользуются предыдущие настройки.",
+                    e
+                ),
+            }
+        }
+    }
+
+    async fn fetch(&self, settings: &Settings, period: &DateRange) -> Result<Vec<PartData>> {
+        let mut source = self.services.source.lock().await;
+        source.reconnect(settings).await?;
+        source.fetch_merged_setups(settings, period).await
+    }
+}
+
+fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/api/setups", get(setups))
+        .route("/api/report/preview", get(report_preview))
+        .with_state(state)
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+async fn setups(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SetupsQuery>,
+) -> Result<Json<Vec<SetupJson>>, ApiError> {
+    let settings = state.settings().await;
+    let period = query.period(Local::now().date_naive(), settings.http.max_days)?;
+    let machine = query.machine()?;
+    let settings = with_norms(&state.services, &settings).await;
+    let parts = state.fetch(&settings, &period).await?;
+    Ok(Json(setup_rows(
+        parts,
+        &settings,
+        machine.as_ref(),
+        query.long,
+    )))
+}
+
+/// Отчет о длительных наладках за период в том виде, в каком он уходит по почте.
+async fn report_preview(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SetupsQuery>,
+) -> Result<Html<String>, ApiError> {
+    let settings = state.settings().await;
+    let period = query.period(Local::now().date_naive(), settings.http.max_days)?;
+    let machine = query.machine()?;
+    let settings = with_norms(&state.services, &settings).await;
+    let parts = state
+        .fetch(&settings, &period)
+        .await?
+        .into_iter()
+        .filter(|part| machine.as_ref().is_none_or(|m| m.is_match(&part.machine)))
+        .collect::<Vec<_>>();
+    let data = crate::source::long_setups(parts, &settings);
+    Ok(Html(generate_html_report(&data, &settings)?))
+}
+
+/// Веб-интерфейс для режима работы по расписанию: при выключенном `http` никогда не завершается.
+pub async fn serve_if_enabled(services: Services, settings: Settings) {
+    if settings.http.enabled {
+        if let Err(e) = serve(services, settings).await {
+            error!("Веб-интерфейс остановлен: {:?}", e);
+        }
+    }
+    // Ошибка веб-интерфейса не должна останавливать отправку отчетов
+    std::future::pending().await
+}
+
+pub async fn serve(services: Services, settings: Settings) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(&settings.http.listen).await?;
+    info!("Веб-интерфейс: http://{}", listener.local_addr()?);
+    let state = Arc::new(AppState {
+        services,
+        settings: TokioMutex::new(settings),
+    });
+    tokio::spawn(state.clone().reload_settings());
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
@@ -1,25 +1,59 @@
-use crate::utils::{retry, MAX_RETRY_ATTEMPTS, RETRY_DELAY};
-use crate::{config::Settings, db::Database, mailer::Mailer};
+use crate::norms::DailyNorms;
+use crate::source::{self, SetupSource};
+use crate::utils::retry;
+use crate::{config::Settings, history::History, mailer::Mailer, outbox::Outbox};
 use eyre::Result;
 use std::sync::Arc;
 use tokio::sync::Mutex as TokioMutex;
 use tracing::info;
 
-pub async fn init_db(settings: &Settings) -> Result<Arc<TokioMutex<Database>>> {
-    let db = Arc::new(TokioMutex::new(
-        retry(MAX_RETRY_ATTEMPTS, RETRY_DELAY, || Database::new(settings)).await?,
+/// Подключения и хранилища, общие для отчетов, оповещений и очереди писем.
+#[derive(Clone)]
+pub struct Services {
+    pub source: Arc<TokioMutex<Box<dyn SetupSource>>>,
+    pub mailer: Arc<TokioMutex<Mailer>>,
+    pub history: Arc<TokioMutex<History>>,
+    pub outbox: Arc<TokioMutex<Outbox>>,
+    /// Нормы наладки, рассчитанные сегодня
+    pub norms: Arc<DailyNorms>,
+}
+
+pub async fn init_services(settings: &Settings) -> Result<Services> {
+    Ok(Services {
+        source: init_source(settings).await?,
+        mailer: init_mailer(settings).await?,
+        history: init_history(settings)?,
+        outbox: init_outbox(settings)?,
+        norms: Arc::default(),
+    })
+}
+
+pub async fn init_source(settings: &Settings) -> Result<Arc<TokioMutex<Box<dyn SetupSource>>>> {
+    let source = Arc::new(TokioMutex::new(
+        retry(&settings.general.retry, || source::open(settings)).await?,
     ));
-    info!("Подключение к базе данных установлено");
-    Ok(db)
+    info!("Источник данных {:?} подключен", settings.source.kind);
+    Ok(source)
 }
 
 pub async fn init_mailer(settings: &Settings) -> Result<Arc<TokioMutex<Mailer>>> {
     let mailer = Arc::new(TokioMutex::new(
-        retry(MAX_RETRY_ATTEMPTS, RETRY_DELAY, || {
-            Mailer::new(&settings.smtp)
-        })
-        .await?,
+        retry(&settings.general.retry, || Mailer::new(&settings.smtp)).await?,
     ));
     info!("Почтовый клиент инициализирован");
     Ok(mailer)
 }
+
+pub fn init_history(settings: &Settings) -> Result<Arc<TokioMutex<History>>> {
+    let path = History::path(settings);
+    let history = Arc::new(TokioMutex::new(History::open(&path)?));
+    info!("Журнал отправок: {}", path.display());
+    Ok(history)
+}
+
+pub fn init_outbox(settings: &Settings) -> Result<Arc<TokioMutex<Outbox>>> {
+    let path = Outbox::path(settings);
+    let outbox = Arc::new(TokioMutex::new(Outbox::open(&path)?));
+    info!("Очередь писем: {}", path.display());
+    Ok(outbox)
+}
@@ -0,0 +1,27 @@
+//! Общий код утилиты lsr и службы lsrs.
+
+pub mod calendar;
+pub mod charts;
+pub mod config;
+pub mod csv_source;
+pub mod db;
+pub mod export;
+pub mod history;
+pub mod http;
+pub mod init;
+pub mod limits;
+pub mod logging;
+pub mod mailer;
+pub mod models;
+pub mod norms;
+pub mod outbox;
+pub mod pattern;
+pub mod reports;
+pub mod scheduler;
+pub mod source;
+pub mod sqlite_source;
+#[cfg(test)]
+mod tests;
+pub mod trends;
+pub mod utils;
+pub mod watcher;
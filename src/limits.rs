@@ -0,0 +1,153 @@
+use crate::config::LimitRuleSettings;
+use crate::pattern::NamePattern;
+use eyre::{bail, Result};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Правило лимита наладки. Незаданное условие подходит под любое значение.
+#[derive(Debug, Clone)]
+pub struct LimitRule {
+    pub origin: String,
+    pub machine: Option<NamePattern>,
+    pub part: Option<NamePattern>,
+    pub setup: Option<i32>,
+    pub limit: i64,
+}
+
+/// Результат подбора лимита: `rule == None` означает `report.default_setup_limit`.
+#[derive(Debug, Clone, Copy)]
+pub struct LimitMatch<'a> {
+    pub limit: i64,
+    pub rule: Option<&'a LimitRule>,
+}
+
+/// Набор правил лимитов, отсортированный по приоритету:
+/// 1. правила с деталью важнее правил без детали;
+/// 2. правила с номером установки важнее правил без него;
+/// 3. точное имя станка важнее маски, маска важнее правила для любого станка;
+/// 4. при равенстве побеждает правило, описанное раньше, `[[limit_rules]]` раньше `[limits]`.
+#[derive(Debug, Clone, Default)]
+pub struct LimitRules {
+    rules: Vec<LimitRule>,
+    default_limit: i64,
+}
+
+impl LimitRule {
+    fn is_match(&self, machine: &str, part: &str, setup: i32) -> bool {
+        self.machine.as_ref().is_none_or(|p| p.is_match(machine))
+            && self.part.as_ref().is_none_or(|p| p.is_match(part))
+            && self.setup.is_none_or(|s| s == setup)
+    }
+
+    fn priority(&self) -> (bool, bool, u8) {
+        let machine_rank = match &self.machine {
+            Some(p) if p.is_exact() => 0,
+            Some(_) => 1,
+            None => 2,
+        };
+        (self.part.is_none(), self.setup.is_none(), machine_rank)
+    }
+}
+
+impl LimitRules {
+    pub fn new(
+        limits: &HashMap<String, i32>,
+        rules: &[LimitRuleSettings],
+        default_limit: i64,
+    ) -> Result<Self> {
+        if default_limit <= 0 {
+            bail!("Лимит наладки по умолчанию должен быть положительным");
+        }
+
+        let mut compiled = Vec::with_capacity(rules.len() + limits.len());
+        for (i, rule) in rules.iter().enumerate() {
+            let origin = format!("limit_rules[{}]", i + 1);
+            if rule.machine.is_none() && rule.part.is_none() && rule.setup.is_none() {
+                bail!("Правило {origin} не содержит ни одного условия");
+            }
+            if rule.limit <= 0 {
+                bail!("Правило {origin}: лимит должен быть положительным");
+            }
+            compiled.push(LimitRule {
+                origin,
+                machine: rule.machine.clone(),
+                part: rule.part.clone(),
+                setup: rule.setup,
+                limit: rule.limit,
+            });
+        }
+
+        let mut machines: Vec<_> = limits.iter().collect();
+        machines.sort();
+        for (machine, limit) in machines {
+            if *limit <= 0 {
+                bail!("Лимит для станка \"{machine}\" должен быть положительным");
+            }
+            compiled.push(LimitRule {
+                origin: "limits".to_string(),
+                machine: Some(NamePattern::Exact(machine.clone())),
+                part: None,
+                setup: None,
+                limit: (*limit).into(),
+            });
+        }
+
+        // Сортировка стабильная, поэтому порядок из настроек сохраняется внутри приоритета
+        compiled.sort_by_key(LimitRule::priority);
+        Ok(Self {
+            rules: compiled,
+            default_limit,
+        })
+    }
+
+    pub fn rules(&self) -> &[LimitRule] {
+        &self.rules
+    }
+
+    /// Все подходящие правила в порядке приоритета, первое из них применяется.
+    pub fn matching<'a>(
+        &'a self,
+        machine: &'a str,
+        part: &'a str,
+        setup: i32,
+    ) -> impl Iterator<Item = &'a LimitRule> + 'a {
+        self.rules
+            .iter()
+            .filter(move |r| r.is_match(machine, part, setup))
+    }
+
+    pub fn find(&self, machine: &str, part: &str, setup: i32) -> LimitMatch<'_> {
+        match self.rules.iter().find(|r| r.is_match(machine, part, setup)) {
+            Some(rule) => LimitMatch {
+                limit: rule.limit,
+                rule: Some(rule),
+            },
+            None => LimitMatch {
+                limit: self.default_limit,
+                rule: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for LimitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut conditions = Vec::new();
+        if let Some(machine) = &self.machine {
+            conditions.push(format!("станок \"{machine}\""));
+        }
+        if let Some(part) = &self.part {
+            conditions.push(format!("деталь \"{part}\""));
+        }
+        if let Some(setup) = self.setup {
+            conditions.push(format!("установка {setup}"));
+        }
+        write!(
+            f,
+            "{}: {} мин [{}]",
+            conditions.join(", "),
+            self.limit,
+            self.origin
+        )
+    }
+}
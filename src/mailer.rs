@@ -1,41 +1,83 @@
 use crate::{
-    config::{Settings, SmtpSettings},
+    charts::{render_png, Chart},
+    config::{Settings, SmtpSecurity, SmtpSettings},
+    export::build_attachments,
     models::PartData,
-    reports::generate_html_report,
+    reports::{generate_text_report, render_html_report},
+    utils::PermanentError,
 };
+use async_native_tls::{Certificate, TlsConnector};
 use async_smtp::{
-    authentication::{Credentials, Mechanism, DEFAULT_ENCRYPTED_MECHANISMS},
-    Envelope, SendableEmail, SmtpClient, SmtpTransport,
+    authentication::{Credentials, DEFAULT_ENCRYPTED_MECHANISMS},
+    extension::{ClientId, ServerInfo},
+    EmailAddress, Envelope, SendableEmail, SmtpClient, SmtpTransport,
 };
-use eyre::{eyre, Result};
-use tokio::{io::BufStream, net::TcpStream};
-use tracing::{debug, error, info};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Local;
+use eyre::{Context, Result};
+use std::path::Path;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufStream},
+    net::TcpStream,
+};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+    /// Картинка, показываемая в HTML по `cid:`, а не отдельным вложением
+    pub content_id: Option<String>,
+}
+
+/// Соединение с почтовым сервером: обычное TCP или TLS.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
 
 pub struct Mailer {
-    transport: SmtpTransport<BufStream<TcpStream>>,
-    creds: Credentials,
-    envelope: Envelope,
+    transport: SmtpTransport<BufStream<Box<dyn Connection>>>,
+    /// `None`, если `smtp.username` пуст и сервер принимает письма без авторизации
+    creds: Option<Credentials>,
+    from: EmailAddress,
 }
 
 impl Mailer {
     pub async fn new(settings: &SmtpSettings) -> Result<Self> {
-        let stream = BufStream::new(
-            TcpStream::connect(format!("{}:{}", settings.server, settings.port)).await?,
-        );
-        let client = SmtpClient::new();
-
-        let transport = SmtpTransport::new(client, stream).await?;
-        let creds = Credentials::new(settings.username.clone(), settings.password.clone());
-        let envelope = Envelope::new(
-            Some(settings.from.parse().unwrap()),
-            settings.to.iter().flat_map(|r| r.parse()).collect(),
-        )
-        .unwrap();
+        let tcp = TcpStream::connect((settings.server.as_str(), settings.port)).await?;
+        let transport = match settings.security {
+            SmtpSecurity::None => {
+                let stream: Box<dyn Connection> = Box::new(tcp);
+                SmtpTransport::new(SmtpClient::new(), BufStream::new(stream)).await?
+            }
+            SmtpSecurity::Tls => {
+                let stream = tls_connect(settings, tcp).await?;
+                SmtpTransport::new(SmtpClient::new(), BufStream::new(stream)).await?
+            }
+            SmtpSecurity::Starttls => {
+                let plain = SmtpTransport::new(SmtpClient::new(), BufStream::new(tcp)).await?;
+                let tcp = plain.starttls().await?.into_inner();
+                let stream = tls_connect(settings, tcp).await?;
+                // После STARTTLS сервер не шлет приветствие повторно, сразу EHLO
+                SmtpTransport::new(SmtpClient::new().without_greeting(), BufStream::new(stream))
+                    .await?
+            }
+        };
+        let creds = (!settings.username.is_empty())
+            .then(|| Credentials::new(settings.username.clone(), settings.password.clone()));
+        let from = settings.from.parse().map_err(|e| {
+            PermanentError(format!(
+                "Неверный адрес отправителя {}: {}",
+                settings.from, e
+            ))
+        })?;
 
         Ok(Self {
             transport,
             creds,
-            envelope,
+            from,
         })
     }
 
@@ -44,72 +86,306 @@ impl Mailer {
         Ok(())
     }
 
-    pub async fn send_report(
-        &mut self,
+    /// Готовое письмо с отчетом: HTML, текстовая версия и вложения из настроек.
+    pub fn compose_report(
+        &self,
         subject: &str,
         parts: &[PartData],
         sender_name: &str,
         settings: &Settings,
-    ) -> Result<()> {
-        if parts.is_empty() {
-            info!("Длительных наладок по заданным критериям не было, отправлять нечего.");
-            return Ok(());
-        }
-        let html_body = generate_html_report(parts, settings)?;
-        let email = SendableEmail::new(
-            self.envelope.clone(),
-            self.format_email(subject, html_body, sender_name)?
-                .as_bytes()
-                .to_vec(),
-        );
-        match self
-            .transport
-            .try_login(&self.creds, DEFAULT_ENCRYPTED_MECHANISMS)
-            .await
-        {
-            Ok(_) => {
-                debug!("Login successful using DEFAULT_ENCRYPTED_MECHANISMS");
-            }
-            Err(try_login_err) => {
-                error!("Try Login Error: {try_login_err:#?}");
+        recipients: &[String],
+    ) -> Result<String> {
+        let report = render_html_report(parts, settings)?;
+        let text_body = generate_text_report(parts, settings)?;
+        let mut attachments = inline_images(&report.charts);
+        attachments.extend(build_attachments(parts, settings)?);
+        self.compose(
+            subject,
+            &report.html,
+            &text_body,
+            &attachments,
+            sender_name,
+            recipients,
+        )
+    }
 
-                if let Err(logout_err) = self.transport.quit().await {
-                    error!("Logout Error: {logout_err:#?}");
-                    return Err(logout_err.into());
-                }
+    pub fn compose(
+        &self,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+        attachments: &[Attachment],
+        sender_name: &str,
+        recipients: &[String],
+    ) -> Result<String> {
+        let envelope = self.envelope(recipients)?;
+        self.format_email(
+            &envelope,
+            subject,
+            html_body,
+            text_body,
+            attachments,
+            sender_name,
+        )
+    }
 
-                self.transport.auth(Mechanism::Plain, &self.creds).await?;
-                debug!("Authenticated using Mechanism::Plain");
-            }
-        }
+    /// Отправляет готовое письмо, например из очереди.
+    pub async fn send_raw(&mut self, recipients: &[String], message: &[u8]) -> Result<()> {
+        let email = SendableEmail::new(self.envelope(recipients)?, message.to_vec());
+        self.login().await?;
 
         if let Err(send_err) = self.transport.send(email).await {
             error!("Email send error: {send_err:#?}");
-            Err(eyre!("Email send error: {send_err:#?}"))
+            Err(eyre::Report::new(send_err).wrap_err("Ошибка отправки письма"))
         } else {
             debug!("Email sent successfully");
             Ok(())
         }
     }
 
-    pub fn format_email(&self, subject: &str, body: String, sender_name: &str) -> Result<String> {
-        let from_email = self
-            .envelope
+    fn envelope(&self, recipients: &[String]) -> Result<Envelope> {
+        let to = recipients
+            .iter()
+            .map(|r| {
+                r.parse().map_err(|e| {
+                    PermanentError(format!("Неверный адрес получателя {}: {}", r, e)).into()
+                })
+            })
+            .collect::<Result<Vec<EmailAddress>>>()?;
+        Envelope::new(Some(self.from.clone()), to).map_err(|e| PermanentError(e.to_string()).into())
+    }
+
+    pub async fn login(&mut self) -> Result<()> {
+        let Some(creds) = &self.creds else {
+            return Ok(());
+        };
+        // try_login молча пропускает авторизацию, если подходящего способа нет,
+        // поэтому поддерживаемые сервером способы запрашиваются повторным EHLO
+        let ehlo = self.transport.get_mut().ehlo(ClientId::default()).await?;
+        let server_info = ServerInfo::from_response(&ehlo)?;
+        let Some(mechanism) = DEFAULT_ENCRYPTED_MECHANISMS
+            .iter()
+            .copied()
+            .find(|m| server_info.supports_auth_mechanism(*m))
+        else {
+            return Err(PermanentError(
+                "Почтовый сервер не поддерживает авторизацию PLAIN или LOGIN, \
+                 проверьте smtp.security или очистите smtp.username"
+                    .into(),
+            )
+            .into());
+        };
+        self.transport
+            .auth(mechanism, creds)
+            .await
+            .wrap_err("Ошибка авторизации на почтовом сервере")?;
+        debug!("Authenticated using {mechanism}");
+        Ok(())
+    }
+
+    /// Письмо `multipart/alternative` с текстовой и HTML-версией отчета, при наличии
+    /// вложений вложенное в `multipart/mixed`. Картинки с `content_id` вкладываются
+    /// вместе с HTML в `multipart/related`. Заголовки кодируются по RFC 2047, части - в base64.
+    pub fn format_email(
+        &self,
+        envelope: &Envelope,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+        attachments: &[Attachment],
+        sender_name: &str,
+    ) -> Result<String> {
+        let from_email = envelope
             .from()
             .ok_or_else(|| eyre::eyre!("Invalid from email"))?
             .to_string();
-        let from = format!("\"{}\" <{}>", sender_name, from_email);
-        let to = self
-            .envelope
+        let from = format!("{} <{}>", encode_header(sender_name), from_email);
+        let to = envelope
             .to()
             .iter()
             .map(|addr| addr.to_string())
             .collect::<Vec<_>>()
             .join(", ");
+        let domain = from_email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .unwrap_or("localhost");
+        let id = Uuid::new_v4().simple();
+        let alternative_boundary = format!("lsr-alt-{id}");
+        let related_boundary = format!("lsr-rel-{id}");
+        let mixed_boundary = format!("lsr-mixed-{id}");
+        let (images, attachments): (Vec<&Attachment>, Vec<&Attachment>) =
+            attachments.iter().partition(|a| a.content_id.is_some());
+
+        let mut email = String::new();
+        email.push_str(&format!("From: {from}\r\n"));
+        email.push_str(&format!("To: {to}\r\n"));
+        email.push_str(&format!("Subject: {}\r\n", encode_header(subject)));
+        email.push_str(&format!("Date: {}\r\n", Local::now().to_rfc2822()));
+        email.push_str(&format!("Message-ID: <{id}@{domain}>\r\n"));
+        email.push_str("MIME-Version: 1.0\r\n");
+        if !attachments.is_empty() {
+            email.push_str(&format!(
+                "Content-Type: multipart/mixed; boundary=\"{mixed_boundary}\"\r\n\r\n"
+            ));
+            email.push_str(&format!("--{mixed_boundary}\r\n"));
+        }
+
+        email.push_str(&format!(
+            "Content-Type: multipart/alternative; boundary=\"{alternative_boundary}\"\r\n\r\n"
+        ));
+        for (content_type, body) in [("text/plain", text_body), ("text/html", html_body)] {
+            email.push_str(&format!("--{alternative_boundary}\r\n"));
+            if content_type == "text/html" && !images.is_empty() {
+                email.push_str(&format!(
+                    "Content-Type: multipart/related; boundary=\"{related_boundary}\"\r\n\r\n"
+                ));
+                email.push_str(&format!("--{related_boundary}\r\n"));
+            }
+            email.push_str(&format!("Content-Type: {content_type}; charset=utf-8\r\n"));
+            email.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+            email.push_str(&encode_body(body.as_bytes()));
+        }
+        if !images.is_empty() {
+            for image in &images {
+                let filename = encode_header(&image.filename);
+                email.push_str(&format!("--{related_boundary}\r\n"));
+                email.push_str(&format!(
+                    "Content-Type: {}; name=\"{}\"\r\n",
+                    image.content_type, filename
+                ));
+                email.push_str(&format!(
+                    "Content-ID: <{}>\r\n",
+                    image.content_id.as_deref().unwrap_or_default()
+                ));
+                email.push_str(&format!(
+                    "Content-Disposition: inline; filename=\"{filename}\"\r\n"
+                ));
+                email.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+                email.push_str(&encode_body(&image.data));
+            }
+            email.push_str(&format!("--{related_boundary}--\r\n"));
+        }
+        email.push_str(&format!("--{alternative_boundary}--\r\n"));
+
+        if !attachments.is_empty() {
+            for attachment in attachments {
+                let filename = encode_header(&attachment.filename);
+                email.push_str(&format!("--{mixed_boundary}\r\n"));
+                email.push_str(&format!(
+                    "Content-Type: {}; name=\"{}\"\r\n",
+                    attachment.content_type, filename
+                ));
+                email.push_str(&format!(
+                    "Content-Disposition: attachment; filename=\"{filename}\"\r\n"
+                ));
+                email.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+                email.push_str(&encode_body(&attachment.data));
+            }
+            email.push_str(&format!("--{mixed_boundary}--\r\n"));
+        }
+        Ok(email)
+    }
+}
+
+/// PNG-версии графиков для почтовых клиентов без поддержки SVG. График, который
+/// не удалось нарисовать, пропускается: в остальных клиентах он виден как SVG.
+fn inline_images(charts: &[Chart]) -> Vec<Attachment> {
+    charts
+        .iter()
+        .filter_map(|chart| match render_png(chart) {
+            Ok(data) => Some(Attachment {
+                filename: format!("{}.png", chart.cid),
+                content_type: "image/png",
+                data,
+                content_id: Some(chart.cid.clone()),
+            }),
+            Err(e) => {
+                warn!("Не удалось нарисовать график {}: {:?}", chart.cid, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Устанавливает TLS поверх `tcp` с проверкой сертификата сервера по системному
+/// хранилищу и сертификатам из `smtp.ca_file`.
+async fn tls_connect(settings: &SmtpSettings, tcp: TcpStream) -> Result<Box<dyn Connection>> {
+    let mut connector = TlsConnector::new();
+    if let Some(ca_file) = &settings.ca_file {
+        for cert in load_ca_bundle(ca_file)? {
+            connector = connector.add_root_certificate(cert);
+        }
+    }
+    let stream = connector
+        .connect(settings.server.as_str(), tcp.compat())
+        .await
+        .wrap_err_with(|| format!("Ошибка TLS-соединения с {}", settings.server))?;
+    Ok(Box::new(stream.compat()))
+}
+
+/// Все сертификаты из PEM-файла.
+fn load_ca_bundle(path: &Path) -> Result<Vec<Certificate>> {
+    let pem = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Не удалось прочитать {}", path.display()))?;
+    let certs = pem_certificates(&pem)
+        .into_iter()
+        .map(|block| Certificate::from_pem(block.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err_with(|| format!("Неверный сертификат в {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(PermanentError(format!("В {} нет сертификатов", path.display())).into());
+    }
+    Ok(certs)
+}
+
+/// Блоки `CERTIFICATE` из PEM-текста, по одному на сертификат.
+pub fn pem_certificates(pem: &str) -> Vec<&str> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut blocks = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find(BEGIN) {
+        let Some(len) = rest[start..].find(END) else {
+            break;
+        };
+        let end = start + len + END.len();
+        blocks.push(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    blocks
+}
+
+/// Кодирует значение заголовка по RFC 2047, если в нем есть не-ASCII символы.
+/// Каждое закодированное слово не длиннее 75 символов и не разрывает символы UTF-8.
+pub fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    // "=?UTF-8?B?" + "?=" занимают 12 символов, 45 байт дают 60 символов base64
+    const MAX_CHUNK_BYTES: usize = 45;
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in value.chars() {
+        if chunk.len() + c.len_utf8() > MAX_CHUNK_BYTES {
+            words.push(format!("=?UTF-8?B?{}?=", BASE64.encode(&chunk)));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    if !chunk.is_empty() {
+        words.push(format!("=?UTF-8?B?{}?=", BASE64.encode(&chunk)));
+    }
+    words.join("\r\n ")
+}
 
-        Ok(format!(
-            "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/html; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}",
-            from, to, subject, body
-        ))
+/// Base64 с переносом строк через 76 символов (RFC 2045).
+pub fn encode_body(body: &[u8]) -> String {
+    let encoded = BASE64.encode(body);
+    let mut wrapped = String::with_capacity(encoded.len() + encoded.len() / 76 * 2 + 2);
+    for line in encoded.as_bytes().chunks(76) {
+        wrapped.push_str(std::str::from_utf8(line).expect("base64 всегда ASCII"));
+        wrapped.push_str("\r\n");
     }
+    wrapped
 }
@@ -1,35 +1,66 @@
-mod config;
-mod db;
-mod init;
-mod logging;
-mod mailer;
-mod models;
-mod reports;
-mod tests;
-mod utils;
+mod cli;
 
-use config::Settings;
+use clap::Parser;
+use cli::{Cli, Command};
 use eyre::Result;
-use init::{init_db, init_mailer};
-use logging::{init_logger, LoggerLayers};
-use reports::{calc_delay, send_report_with_retry};
+use long_setups_reporter::config::Settings;
+use long_setups_reporter::init::init_services;
+use long_setups_reporter::logging::{init_logger, LoggerLayers};
+use long_setups_reporter::scheduler::run_loop;
+use long_setups_reporter::watcher::watch_if_enabled;
+use long_setups_reporter::{http, outbox};
 use std::io::Write;
-use std::sync::Arc;
 use tokio::signal;
-use tokio::time::{sleep, Duration as TokioDuration};
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info};
 
 #[tokio::main]
+#[allow(clippy::print_literal)]
 async fn main() -> Result<()> {
     print!("\x1B]0;{}\x07", "Long Setup Reporter");
     std::io::stdout().flush()?;
-    let mut settings = Settings::new()?;
+    let cli = Cli::parse();
+    let settings = match &cli.config {
+        Some(path) => Settings::from_file(path)?,
+        None => Settings::new()?,
+    };
 
     let _guard = init_logger(&settings, LoggerLayers::Both);
     info!("Приложение запущено");
-    let db = init_db(&settings).await?;
-    let mailer = init_mailer(&settings).await?;
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(settings).await,
+        Command::Watch => cli::watch(settings).await,
+        Command::SendNow => cli::send_now(&settings).await,
+        Command::Preview { date, output } => cli::preview(&settings, date, &output).await,
+        Command::CheckConfig => cli::check_config(&settings),
+        Command::TestDb => cli::test_db(&settings).await,
+        Command::TestSmtp => cli::test_smtp(&settings).await,
+        Command::ExplainLimit {
+            machine,
+            part,
+            setup,
+        } => {
+            cli::explain_limit(&settings, &machine, &part, setup);
+            Ok(())
+        }
+        Command::History { limit } => cli::history(&settings, limit),
+        Command::Outbox { action } => cli::outbox(&settings, action).await,
+        Command::Report {
+            from,
+            to,
+            send,
+            output,
+        } => cli::report(&settings, from, to, send, output).await,
+    }
+}
+
+async fn run(settings: Settings) -> Result<()> {
+    let services = init_services(&settings).await?;
     debug!("Приложение инициализировано с параметрами:\n{settings}");
+    let alerts_task = watch_if_enabled(services.clone(), settings.clone());
+    let outbox_task = outbox::run(services.clone(), settings.clone());
+    let http_task = http::serve_if_enabled(services.clone(), settings.clone());
+    let main_task = run_loop(services, settings);
     let ctrl_c_handler = async {
         signal::ctrl_c()
             .await
@@ -37,39 +68,14 @@ async fn main() -> Result<()> {
         info!("Получен сигнал Ctrl+C, завершение работы...");
     };
 
-    let main_task = async {
-        loop {
-            let secs = match calc_delay(&settings) {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Не удалость вычислить время ожидания.\n{}", e);
-                    break;
-                }
-            };
-            sleep(TokioDuration::from_secs(secs)).await;
-
-            if let Err(e) = settings.update() {
-                warn!("Не удалось обновить параметры приложения: {}\nИспользуются предыдущие настройки.", e);
-                debug!("Текущие параметры приложения:\n{}", settings);
-            } else {
-                debug!("Параметры приложения успешно обновлены:\n{}", settings);
-            }
-
-            if let Err(e) =
-                send_report_with_retry(Arc::clone(&db), Arc::clone(&mailer), &settings).await
-            {
-                error!("Все попытки отправки отчета исчерпаны: {:?}", e);
-            } else {
-                info!("Отчёт успешно отправлен");
-            }
-        }
-    };
-
     tokio::select! {
         _ = ctrl_c_handler => {
             info!("Приложение завершено.");
         }
         _ = main_task => {}
+        _ = alerts_task => {}
+        _ = outbox_task => {}
+        _ = http_task => {}
     }
 
     Ok(())
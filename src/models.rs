@@ -1,9 +1,47 @@
-use chrono::{NaiveDateTime, Timelike, Duration};
+use crate::calendar::{BreakWindow, ShiftCalendar};
+use crate::config::{Settings, TimePolicy};
+use crate::norms::Norm;
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, Weekday};
 use eyre::Result;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::fmt;
 use tiberius::Row;
 
-#[derive(Debug)]
+/// Период отчета по сменным датам, включая обе границы.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+/// Наладка с рассчитанными показателями: одна строка выгрузки отчета.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportRow {
+    pub machine: String,
+    pub part_name: String,
+    pub setup: i32,
+    pub order: String,
+    pub operator: String,
+    pub shift: String,
+    pub start_setup_time: NaiveDateTime,
+    pub end_setup_time: NaiveDateTime,
+    /// Время от начала наладки до запуска обработки без промежутков между частями
+    pub gross_minutes: i64,
+    /// Чистое время по `time_policy`, с ним сравнивается лимит
+    pub setup_minutes: i64,
+    /// Что вычтено из общего времени
+    pub time_policy: String,
+    pub breaks_minutes: i64,
+    pub limit: i64,
+    pub overrun: i64,
+    pub downtimes: f64,
+    pub operators_comment: String,
+    /// Норма по истории, если она рассчитана и наладок достаточно
+    pub norm: Option<Norm>,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct PartData {
     pub part_name: String,
     pub setup: i32,
@@ -14,10 +52,128 @@ pub struct PartData {
     pub end_setup_time: NaiveDateTime,
     pub operators_comment: String,
     pub downtimes: f64,
+    /// Промежутки между частями наладки, записанной несколькими строками.
+    /// В длительность наладки не входят.
+    pub gaps: Vec<(NaiveDateTime, NaiveDateTime)>,
+}
+
+/// Наладка однозначно определяется станком, заказом, деталью, установкой и временем начала.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SetupKey {
+    pub machine: String,
+    pub order: String,
+    pub part_name: String,
+    pub setup: i32,
+    pub start_setup_time: NaiveDateTime,
+}
+
+impl From<&PartData> for SetupKey {
+    fn from(part: &PartData) -> Self {
+        Self {
+            machine: part.machine.clone(),
+            order: part.order.clone(),
+            part_name: part.part_name.clone(),
+            setup: part.setup,
+            start_setup_time: part.start_setup_time,
+        }
+    }
+}
+
+impl DateRange {
+    pub fn new(from: NaiveDate, to: NaiveDate) -> Result<Self> {
+        if from > to {
+            eyre::bail!(
+                "Начало периода {} позже его окончания {}",
+                from.format("%d.%m.%Y"),
+                to.format("%d.%m.%Y")
+            );
+        }
+        Ok(Self { from, to })
+    }
+
+    pub fn single_day(day: NaiveDate) -> Self {
+        Self { from: day, to: day }
+    }
+
+    pub fn yesterday() -> Self {
+        let today = Local::now().date_naive();
+        Self::single_day(today.pred_opt().unwrap_or(today))
+    }
+
+    /// Прошлая неделя с понедельника по воскресенье относительно `today`.
+    pub fn previous_week(today: NaiveDate) -> Self {
+        let monday = today.week(Weekday::Mon).first_day() - Duration::weeks(1);
+        Self {
+            from: monday,
+            to: monday + Duration::days(6),
+        }
+    }
+
+    /// Прошлый календарный месяц относительно `today`.
+    pub fn previous_month(today: NaiveDate) -> Self {
+        let first_of_month = today.with_day(1).unwrap_or(today);
+        let to = first_of_month.pred_opt().unwrap_or(first_of_month);
+        Self {
+            from: to.with_day(1).unwrap_or(to),
+            to,
+        }
+    }
+
+    /// Предыдущий период той же длины, для календарного месяца - предыдущий месяц.
+    pub fn previous(&self) -> Self {
+        let whole_month = self.from.day() == 1
+            && self.from.month() == self.to.month()
+            && self.from.year() == self.to.year()
+            && self.to.succ_opt().is_some_and(|next| next.day() == 1);
+        if whole_month {
+            return Self::previous_month(self.from);
+        }
+        let days = self.days();
+        Self {
+            from: self.from - Duration::days(days),
+            to: self.from - Duration::days(1),
+        }
+    }
+
+    /// Число дней в периоде, включая обе границы.
+    pub fn days(&self) -> i64 {
+        (self.to - self.from).num_days() + 1
+    }
+
+    pub fn is_single_day(&self) -> bool {
+        self.from == self.to
+    }
+}
+
+impl fmt::Display for DateRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_single_day() {
+            write!(f, "{}", self.from.format("%d.%m.%Y"))
+        } else {
+            write!(
+                f,
+                "{} - {}",
+                self.from.format("%d.%m.%Y"),
+                self.to.format("%d.%m.%Y")
+            )
+        }
+    }
 }
 
 impl PartData {
     pub fn from_sql_row(row: &Row) -> Result<Self> {
+        let end_setup_time: NaiveDateTime = row
+            .get("StartMachiningTime")
+            .ok_or_else(|| eyre::eyre!("Missing StartMachiningTime"))?;
+        Self::from_sql_row_with_end(row, end_setup_time)
+    }
+
+    /// Незавершенная наладка: окончанием считается момент `now`.
+    pub fn running_from_sql_row(row: &Row, now: NaiveDateTime) -> Result<Self> {
+        Self::from_sql_row_with_end(row, now)
+    }
+
+    fn from_sql_row_with_end(row: &Row, end_setup_time: NaiveDateTime) -> Result<Self> {
         let part_name: &str = row
             .get("PartName")
             .ok_or_else(|| eyre::eyre!("Missing PartName"))?;
@@ -36,15 +192,12 @@ impl PartData {
         let start_setup_time: NaiveDateTime = row
             .get("StartSetupTime")
             .ok_or_else(|| eyre::eyre!("Missing StartSetupTime"))?;
-        let end_setup_time: NaiveDateTime = row
-            .get("StartMachiningTime")
-            .ok_or_else(|| eyre::eyre!("Missing EndSetupTime"))?;
         let operator_comment: &str = row
             .get("OperatorComment")
-            .ok_or_else(|| eyre::eyre!("Missing Order"))?;
+            .ok_or_else(|| eyre::eyre!("Missing OperatorComment"))?;
         let downtimes: f64 = row
             .get("SetupDowntimes")
-            .ok_or_else(|| eyre::eyre!("Missing Downtimes"))?;
+            .ok_or_else(|| eyre::eyre!("Missing SetupDowntimes"))?;
         Ok(Self {
             part_name: part_name.to_string(),
             setup,
@@ -55,101 +208,198 @@ impl PartData {
             end_setup_time,
             operators_comment: operator_comment.to_string(),
             downtimes,
+            gaps: Vec::new(),
         })
     }
 
-    pub fn breaks_between(&self, calc_on_end: bool) -> Duration {
-        let mut day_shift_first_break = NaiveDateTime::new(chrono::NaiveDate::from_ymd_opt(1, 1, 1).unwrap(), chrono::NaiveTime::from_hms_opt(9, 0, 0).unwrap());  // 09:00:00
-        let mut day_shift_second_break = NaiveDateTime::new(chrono::NaiveDate::from_ymd_opt(1, 1, 1).unwrap(), chrono::NaiveTime::from_hms_opt(12, 30, 0).unwrap()); // 12:30:00
-        let mut day_shift_third_break = NaiveDateTime::new(chrono::NaiveDate::from_ymd_opt(1, 1, 1).unwrap(), chrono::NaiveTime::from_hms_opt(15, 15, 0).unwrap());  // 15:15:00
+    /// Перерывы во время наладки, кроме попавших в промежутки между ее частями.
+    pub fn breaks_between(&self, calendar: &ShiftCalendar, calc_on_end: bool) -> Duration {
+        self.gaps.iter().fold(
+            calendar.breaks_between(self.start_setup_time, self.end_setup_time, calc_on_end),
+            |breaks, &(from, to)| breaks - calendar.breaks_between(from, to, calc_on_end),
+        )
+    }
 
-        let mut night_shift_first_break = NaiveDateTime::new(chrono::NaiveDate::from_ymd_opt(1, 1, 1).unwrap(), chrono::NaiveTime::from_hms_opt(22, 30, 0).unwrap()); // 22:30:00
-        let mut night_shift_second_break = NaiveDateTime::new(chrono::NaiveDate::from_ymd_opt(1, 1, 1).unwrap(), chrono::NaiveTime::from_hms_opt(1, 30, 0).unwrap());  // 01:30:00
-        let mut night_shift_third_break = NaiveDateTime::new(chrono::NaiveDate::from_ymd_opt(1, 1, 1).unwrap(), chrono::NaiveTime::from_hms_opt(4, 30, 0).unwrap());   // 04:30:00
+    /// Перерывы, вычитаемые из наладки, без перерывов в промежутках между частями.
+    pub fn break_windows(&self, calendar: &ShiftCalendar) -> Vec<BreakWindow> {
+        calendar
+            .break_windows(self.start_setup_time, self.end_setup_time)
+            .into_iter()
+            .filter(|window| {
+                !self
+                    .gaps
+                    .iter()
+                    .any(|&(from, to)| window.start > from && window.start <= to)
+            })
+            .collect()
+    }
 
-        if !calc_on_end {
-            day_shift_first_break = day_shift_first_break - Duration::minutes(14);
-            day_shift_second_break = day_shift_second_break - Duration::minutes(29);
-            day_shift_third_break = day_shift_third_break - Duration::minutes(14);
-            night_shift_first_break = night_shift_first_break - Duration::minutes(29);
-            night_shift_second_break = night_shift_second_break - Duration::minutes(29);
-            night_shift_third_break = night_shift_third_break - Duration::minutes(29);
-        }
+    /// Общее время наладки без промежутков между частями.
+    pub fn gross_duration(&self) -> Duration {
+        self.end_setup_time
+            .signed_duration_since(self.start_setup_time)
+            - self.gaps_duration()
+    }
 
-        let mut breaks = Duration::zero();
-        let start_time = self.start_setup_time.num_seconds_from_midnight();
-        let mut end_time = self.end_setup_time.num_seconds_from_midnight();
+    /// Чистое время наладки в минутах по правилу `policy`.
+    pub fn net_minutes(&self, calendar: &ShiftCalendar, policy: TimePolicy) -> i64 {
+        let gross = self.gross_duration();
+        let net = match policy {
+            TimePolicy::Gross => gross,
+            TimePolicy::MinusBreaks => gross - self.breaks_between(calendar, true),
+            TimePolicy::MinusBreaksAndDowntimes => {
+                let downtimes = Duration::seconds((self.downtimes * 60.0).round() as i64);
+                gross - self.breaks_between(calendar, true) - downtimes
+            }
+        };
+        // Простои могут быть записаны с ошибкой и превысить длительность наладки
+        net.num_minutes().max(0)
+    }
 
-        if start_time > end_time {
-            night_shift_second_break = night_shift_second_break + Duration::days(1);
-            night_shift_third_break = night_shift_third_break + Duration::days(1);
-            end_time += 24 * 60 * 60;
-        }
+    /// Чистое время наладки по правилу для станка из настроек.
+    pub fn setup_minutes(&self, settings: &Settings) -> i64 {
+        self.net_minutes(&settings.shifts, settings.time_policy(&self.machine))
+    }
 
-        if day_shift_first_break.num_seconds_from_midnight() > start_time && day_shift_first_break.num_seconds_from_midnight() <= end_time {
-            breaks = breaks + Duration::minutes(15);
-            if !calc_on_end {
-                end_time += 15 * 60;
-            }
-        }
+    fn gaps_duration(&self) -> Duration {
+        self.gaps
+            .iter()
+            .fold(Duration::zero(), |total, &(from, to)| total + (to - from))
+    }
 
-        if day_shift_second_break.num_seconds_from_midnight() > start_time && day_shift_second_break.num_seconds_from_midnight() <= end_time {
-            breaks = breaks + Duration::minutes(30);
-            if !calc_on_end {
-                end_time += 30 * 60;
-            }
-        }
+    /// Части одной наладки: тот же станок, заказ, деталь и установка.
+    fn merge_key(&self) -> (String, String, String, i32) {
+        (
+            self.machine.clone(),
+            self.order.clone(),
+            self.part_name.clone(),
+            self.setup,
+        )
+    }
 
-        if day_shift_third_break.num_seconds_from_midnight() > start_time && day_shift_third_break.num_seconds_from_midnight() <= end_time {
-            breaks = breaks + Duration::minutes(15);
-            if !calc_on_end {
-                end_time += 15 * 60;
-            }
+    /// Присоединяет следующую часть той же наладки: время между частями становится
+    /// промежутком, простои суммируются, операторы и комментарии собираются без повторов.
+    fn append(&mut self, next: PartData) {
+        if next.start_setup_time > self.end_setup_time {
+            self.gaps.push((self.end_setup_time, next.start_setup_time));
         }
+        self.gaps.extend(next.gaps);
+        self.end_setup_time = self.end_setup_time.max(next.end_setup_time);
+        self.downtimes += next.downtimes;
+        append_unique(&mut self.operator, &next.operator, ", ");
+        append_unique(&mut self.operators_comment, &next.operators_comment, "; ");
+    }
+}
 
-        if night_shift_first_break.num_seconds_from_midnight() > start_time && night_shift_first_break.num_seconds_from_midnight() <= end_time {
-            breaks = breaks + Duration::minutes(30);
-            if !calc_on_end {
-                end_time += 30 * 60;
-            }
-        }
+fn append_unique(list: &mut String, value: &str, separator: &str) {
+    if value.is_empty() || list.split(separator).any(|item| item == value) {
+        return;
+    }
+    if !list.is_empty() {
+        list.push_str(separator);
+    }
+    list.push_str(value);
+}
 
-        if night_shift_second_break.num_seconds_from_midnight() > start_time && night_shift_second_break.num_seconds_from_midnight() <= end_time {
-            breaks = breaks + Duration::minutes(30);
-            if !calc_on_end {
-                end_time += 30 * 60;
+/// Собирает наладки, записанные несколькими строками (например, при пересменке),
+/// в одну на каждый станок, заказ, деталь и установку. Время наладки - сумма
+/// времени частей без промежутков между ними. Порядок - по первому появлению.
+pub fn merge_split_setups(parts: Vec<PartData>) -> Vec<PartData> {
+    let mut groups: Vec<Vec<PartData>> = Vec::new();
+    let mut index: HashMap<(String, String, String, i32), usize> = HashMap::new();
+    for part in parts {
+        let key = part.merge_key();
+        match index.get(&key) {
+            Some(&i) => groups[i].push(part),
+            None => {
+                index.insert(key, groups.len());
+                groups.push(vec![part]);
             }
         }
+    }
+    groups
+        .into_iter()
+        .filter_map(|mut group| {
+            group.sort_by_key(|part| part.start_setup_time);
+            let mut fragments = group.into_iter();
+            let mut merged = fragments.next()?;
+            for fragment in fragments {
+                merged.append(fragment);
+            }
+            Some(merged)
+        })
+        .collect()
+}
 
-        if night_shift_third_break.num_seconds_from_midnight() > start_time && night_shift_third_break.num_seconds_from_midnight() <= end_time {
-            breaks = breaks + Duration::minutes(30);
+impl ReportRow {
+    pub fn new(part: &PartData, settings: &Settings) -> Self {
+        let setup_minutes = part.setup_minutes(settings);
+        let limit = settings.get_setup_limit(&part.machine, &part.part_name, part.setup);
+        Self {
+            machine: part.machine.clone(),
+            part_name: part.part_name.clone(),
+            setup: part.setup,
+            order: part.order.clone(),
+            operator: part.operator.clone(),
+            shift: settings
+                .shifts
+                .shift_at(part.start_setup_time)
+                .map(|s| s.name.clone())
+                .unwrap_or_default(),
+            start_setup_time: part.start_setup_time,
+            end_setup_time: part.end_setup_time,
+            gross_minutes: part.gross_duration().num_minutes(),
+            setup_minutes,
+            time_policy: settings
+                .time_policy(&part.machine)
+                .description()
+                .to_string(),
+            breaks_minutes: part.breaks_between(&settings.shifts, true).num_minutes(),
+            limit,
+            overrun: (setup_minutes - limit).max(0),
+            downtimes: part.downtimes,
+            operators_comment: part.operators_comment.clone(),
+            norm: settings
+                .norm(&part.machine, &part.part_name, part.setup)
+                .copied(),
         }
+    }
+}
 
-        breaks
+/// Наладка для журнала: время наладки и перерывы по графику смен.
+pub struct PartDisplay<'a> {
+    part: &'a PartData,
+    calendar: &'a ShiftCalendar,
+}
+
+impl PartData {
+    pub fn display<'a>(&'a self, calendar: &'a ShiftCalendar) -> PartDisplay<'a> {
+        PartDisplay {
+            part: self,
+            calendar,
+        }
     }
 }
 
-impl fmt::Display for PartData {
+impl fmt::Display for PartDisplay<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let setup_duration = self
-            .end_setup_time
-            .signed_duration_since(self.start_setup_time);
-        let breaks_minutes = self.breaks_between(true).num_minutes();
-        let setup_minutes = setup_duration.num_minutes() - breaks_minutes;
-        
+        let part = self.part;
+        let breaks_minutes = part.breaks_between(self.calendar, true).num_minutes();
+        let setup_minutes = part.gross_duration().num_minutes() - breaks_minutes;
+
         write!(
             f,
             "Деталь: {}\n Установка: {}\nМ/Л: {}\nОператор: {}\nНаладка: {} - {} ({} мин.)\nПростои: {} мин.\nПерерывы: {} мин.\nКомментарий оператора: {}",
-            self.part_name,
-            self.setup,
-            self.order,
-            self.operator,
-            self.start_setup_time.format("%d.%m.%y %H:%M:%S"),
-            self.end_setup_time.format("%d.%m.%y %H:%M:%S"),
+            part.part_name,
+            part.setup,
+            part.order,
+            part.operator,
+            part.start_setup_time.format("%d.%m.%y %H:%M:%S"),
+            part.end_setup_time.format("%d.%m.%y %H:%M:%S"),
             setup_minutes,
-            self.downtimes,
+            part.downtimes,
             breaks_minutes,
-            self.operators_comment
+            part.operators_comment
         )
     }
 }
@@ -0,0 +1,234 @@
+use crate::config::Settings;
+use crate::init::Services;
+use crate::models::{DateRange, PartData};
+use crate::scheduler::CATCH_UP_RETRY_DELAY;
+use crate::source::SetupSource;
+use crate::utils::retry;
+use chrono::{Duration, Local, NaiveDate, NaiveDateTime};
+use eyre::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard, PoisonError};
+use tokio::sync::Mutex as TokioMutex;
+use tracing::{info, warn};
+
+/// Норма времени наладки детали на станке по истории, мин.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Norm {
+    pub median: i64,
+    pub p90: i64,
+    /// Сколько наладок вошло в расчет
+    pub count: usize,
+}
+
+/// Нормы по станку, детали и номеру установки.
+#[derive(Debug, Clone, Default)]
+pub struct Norms {
+    norms: HashMap<(String, String, i32), Norm>,
+}
+
+impl Norm {
+    /// Медиана и 90-й процентиль (по ближайшему рангу), `None` для пустого списка.
+    pub fn from_minutes(mut minutes: Vec<i64>) -> Option<Self> {
+        if minutes.is_empty() {
+            return None;
+        }
+        minutes.sort_unstable();
+        let count = minutes.len();
+        let median = if count.is_multiple_of(2) {
+            (minutes[count / 2 - 1] + minutes[count / 2]) / 2
+        } else {
+            minutes[count / 2]
+        };
+        let p90_rank = (count * 9).div_ceil(10);
+        Some(Self {
+            median,
+            p90: minutes[p90_rank - 1],
+            count,
+        })
+    }
+
+    /// Лимит по норме: p90 с запасом `margin_percent`.
+    pub fn limit(&self, margin_percent: f64) -> i64 {
+        (self.p90 as f64 * (1.0 + margin_percent / 100.0)).round() as i64
+    }
+}
+
+impl Norms {
+    /// Нормы по завершенным наладкам `parts`. Время наладки считается так же,
+    /// как при проверке лимита, наладки нулевой длительности не учитываются.
+    pub fn learn(parts: &[PartData], settings: &Settings) -> Self {
+        let mut minutes: HashMap<(String, String, i32), Vec<i64>> = HashMap::new();
+        for part in parts {
+            let setup_minutes = part.setup_minutes(settings);
+            if setup_minutes <= 0 {
+                continue;
+            }
+            minutes
+                .entry((part.machine.clone(), part.part_name.clone(), part.setup))
+                .or_default()
+                .push(setup_minutes);
+        }
+        Self {
+            norms: minutes
+                .into_iter()
+                .filter_map(|(key, minutes)| Norm::from_minutes(minutes).map(|norm| (key, norm)))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, machine: &str, part: &str, setup: i32) -> Option<&Norm> {
+        self.norms
+            .get(&(machine.to_string(), part.to_string(), setup))
+    }
+
+    pub fn len(&self) -> usize {
+        self.norms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.norms.is_empty()
+    }
+}
+
+/// История для норм: `norms.lookback_days` дней до `before`, не включая его.
+pub fn history_period(before: NaiveDate, settings: &Settings) -> DateRange {
+    let to = before.pred_opt().unwrap_or(before);
+    DateRange {
+        from: to - Duration::days(settings.norms.lookback_days - 1),
+        to,
+    }
+}
+
+/// Рассчитывает нормы по наладкам из источника за историю до `before`.
+pub async fn learn(
+    source: &TokioMutex<Box<dyn SetupSource>>,
+    settings: &Settings,
+    before: NaiveDate,
+) -> Result<Norms> {
+    let period = history_period(before, settings);
+    let parts = retry(&settings.general.retry, || async {
+        let mut source = source.lock().await;
+        source.reconnect(settings).await?;
+        source.fetch_merged_setups(settings, &period).await
+    })
+    .await?;
+    let norms = Norms::learn(&parts, settings);
+    info!(
+        "Нормы наладки за {}: наладок {}, сочетаний станка, детали и установки {}",
+        period,
+        parts.len(),
+        norms.len()
+    );
+    Ok(norms)
+}
+
+/// Нормы для отчетов, оповещений и веб-интерфейса: пересчитываются раз в день
+/// по истории до текущего дня, а настройки при этом можно перечитывать сколько угодно часто.
+/// Пока нормы рассчитываются, остальные задачи не ждут, а используют прежние.
+#[derive(Debug, Default)]
+pub struct DailyNorms {
+    state: StdMutex<LearnedNorms>,
+    /// Нормы рассчитывает только одна задача
+    learning: TokioMutex<()>,
+}
+
+#[derive(Debug, Default)]
+struct LearnedNorms {
+    /// Последние рассчитанные нормы и день, по истории до которого они рассчитаны
+    norms: Option<(NaiveDate, Arc<Norms>)>,
+    /// После неудачного расчета следующая попытка не раньше этого времени
+    retry_at: Option<NaiveDateTime>,
+}
+
+impl LearnedNorms {
+    fn is_fresh(&self, today: NaiveDate) -> bool {
+        self.norms.as_ref().is_some_and(|(day, _)| *day == today)
+    }
+
+    fn can_retry(&self, now: NaiveDateTime) -> bool {
+        self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+}
+
+impl DailyNorms {
+    /// Подставляет в `settings` нормы по истории до `now`, если они включены.
+    /// Если историю прочитать не удалось, действуют прежние нормы или лимиты из правил,
+    /// а расчет повторяется через `CATCH_UP_RETRY_DELAY`.
+    pub async fn apply(
+        &self,
+        source: &TokioMutex<Box<dyn SetupSource>>,
+        settings: &mut Settings,
+        now: NaiveDateTime,
+    ) {
+        if !settings.norms.enabled {
+            return;
+        }
+        if let Some(norms) = self.learn_if_due(source, settings, now).await {
+            settings.learned_norms = norms;
+        }
+    }
+
+    async fn learn_if_due(
+        &self,
+        source: &TokioMutex<Box<dyn SetupSource>>,
+        settings: &Settings,
+        now: NaiveDateTime,
+    ) -> Option<Arc<Norms>> {
+        let today = now.date();
+        let previous = {
+            let state = self.state();
+            let previous = state.norms.as_ref().map(|(_, norms)| norms.clone());
+            if state.is_fresh(today) || !state.can_retry(now) {
+                return previous;
+            }
+            previous
+        };
+        // Если нормы уже считает другая задача, ждать ее стоит, только если прежних норм нет
+        let _learning = match (self.learning.try_lock(), &previous) {
+            (Ok(guard), _) => guard,
+            (Err(_), Some(_)) => return previous,
+            (Err(_), None) => self.learning.lock().await,
+        };
+        {
+            let state = self.state();
+            if state.is_fresh(today) || !state.can_retry(now) {
+                return state.norms.as_ref().map(|(_, norms)| norms.clone());
+            }
+        }
+
+        let learned = learn(source, settings, today).await;
+        let mut state = self.state();
+        match learned {
+            Ok(norms) => {
+                state.norms = Some((today, Arc::new(norms)));
+                state.retry_at = None;
+            }
+            Err(e) => {
+                let retry_at = now + Duration::seconds(CATCH_UP_RETRY_DELAY as i64);
+                warn!(
+                    "Не удалось рассчитать нормы наладки, следующая попытка в {}: {:?}",
+                    retry_at.format("%H:%M"),
+                    e
+                );
+                state.retry_at = Some(retry_at);
+            }
+        }
+        state.norms.as_ref().map(|(_, norms)| norms.clone())
+    }
+
+    fn state(&self) -> MutexGuard<'_, LearnedNorms> {
+        // Блокировка не держится во время расчета, поэтому ее никто не ждет долго
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Настройки с нормами из общего для всех задач `services.norms`.
+pub async fn with_norms(services: &Services, settings: &Settings) -> Settings {
+    let mut settings = settings.clone();
+    services
+        .norms
+        .apply(&services.source, &mut settings, Local::now().naive_local())
+        .await;
+    settings
+}
@@ -0,0 +1,463 @@
+use crate::config::{OutboxSettings, Settings};
+use crate::history::{DeliveryStatus, History};
+use crate::init::Services;
+use crate::utils::{classify, ErrorClass};
+use chrono::{Duration, Local, NaiveDateTime};
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::time::{sleep, Duration as TokioDuration};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// Как часто фоновая задача проверяет очередь, в секундах.
+const FLUSH_INTERVAL: u64 = 60;
+/// Через сколько минут незавершенная отправка считается прерванной, например
+/// остановкой процесса, и письмо возвращается в очередь.
+const CLAIM_TIMEOUT: u64 = 60;
+
+/// Письмо в очереди. Сам текст письма лежит рядом в файле `<id>.eml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    #[serde(skip)]
+    pub id: String,
+    pub subject: String,
+    pub to: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub attempts: u32,
+    pub next_attempt: NaiveDateTime,
+    pub last_error: Option<String>,
+    /// Запись журнала отправок, которую нужно обновить после доставки
+    pub delivery_id: Option<i64>,
+    /// Старше `outbox.max_age_hours` или отклонено сервером: автоматически больше не повторяется
+    #[serde(default)]
+    pub expired: bool,
+}
+
+/// Очередь исходящих писем на диске: письмо удаляется из нее только после того,
+/// как почтовый сервер его принял. Отправляемое письмо забирается из очереди
+/// переименованием `<id>.json` в `<id>.sending`, поэтому его не возьмет ни другая
+/// задача, ни другой процесс, например `lsr outbox retry` при работающей службе.
+#[derive(Debug)]
+pub struct Outbox {
+    dir: PathBuf,
+}
+
+impl OutboxEntry {
+    /// Письмо старше `outbox.max_age_hours`, если срок задан.
+    pub fn is_expired(&self, settings: &OutboxSettings, now: NaiveDateTime) -> bool {
+        settings
+            .max_age_hours
+            .is_some_and(|hours| self.created_at + Duration::hours(hours) < now)
+    }
+}
+
+impl Outbox {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Не удалось создать папку очереди {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Папка из `outbox.path` или `outbox` рядом с исполняемым файлом.
+    pub fn path(settings: &Settings) -> PathBuf {
+        match &settings.outbox.path {
+            Some(path) => settings.resolve_path(path),
+            None => env::current_exe()
+                .ok()
+                .and_then(|path| path.parent().map(PathBuf::from))
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("outbox"),
+        }
+    }
+
+    pub fn enqueue(
+        &self,
+        subject: &str,
+        to: &[String],
+        message: &str,
+        delivery_id: Option<i64>,
+        now: NaiveDateTime,
+    ) -> Result<OutboxEntry> {
+        let entry = OutboxEntry {
+            id: format!(
+                "{}-{}",
+                now.format("%Y%m%d-%H%M%S"),
+                Uuid::new_v4().simple()
+            ),
+            subject: subject.to_string(),
+            to: to.to_vec(),
+            created_at: now,
+            attempts: 0,
+            next_attempt: now,
+            last_error: None,
+            delivery_id,
+            expired: false,
+        };
+        // Сначала письмо, потом описание: запись без описания считается недописанной
+        fs::write(self.message_path(&entry.id), message)
+            .wrap_err("Не удалось записать письмо в очередь")?;
+        self.save(&entry)?;
+        Ok(entry)
+    }
+
+    /// Все письма в очереди, старые первыми. Отправляемые сейчас письма не входят.
+    pub fn entries(&self) -> Result<Vec<OutboxEntry>> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().map(|s| s.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            match read_entry(&path, id) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn!("Не удалось прочитать {}: {:?}", path.display(), e),
+            }
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
+
+    /// Забирает письмо на отправку. `None`, если его уже забрали или удалили.
+    /// Возвращается описание с диска, а не из ранее прочитанного списка.
+    pub fn claim(&self, id: &str) -> Result<Option<OutboxEntry>> {
+        let claim_path = self.claim_path(id);
+        match fs::rename(self.meta_path(id), &claim_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Не удалось забрать письмо {id}"));
+            }
+        }
+        // Переименование сохраняет время изменения, а по нему определяется прерванная отправка
+        fs::File::options()
+            .write(true)
+            .open(&claim_path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+            .wrap_err_with(|| format!("Не удалось забрать письмо {id}"))?;
+        read_entry(&claim_path, id.to_string()).map(Some)
+    }
+
+    /// Возвращает в очередь письма, отправка которых не завершилась за `CLAIM_TIMEOUT`.
+    pub fn release_stale(&self) -> Result<()> {
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            if path.extension().is_none_or(|ext| ext != "sending") {
+                continue;
+            }
+            let Some(id) = path.file_stem().map(|s| s.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            let stale = fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|elapsed| elapsed.as_secs() > CLAIM_TIMEOUT * 60);
+            if !stale {
+                continue;
+            }
+            if self.message_path(&id).exists() {
+                warn!(
+                    "Отправка письма {} прервана, письмо возвращено в очередь. \
+                     Возможно, сервер его уже принял",
+                    id
+                );
+                fs::rename(&path, self.meta_path(&id))?;
+            } else {
+                // Письмо отправлено, но его описание не удалось удалить
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn message(&self, entry: &OutboxEntry) -> Result<Vec<u8>> {
+        fs::read(self.message_path(&entry.id))
+            .wrap_err_with(|| format!("Не удалось прочитать письмо {}", entry.id))
+    }
+
+    /// Удаляет забранное письмо из очереди.
+    pub fn remove(&self, entry: &OutboxEntry) -> Result<()> {
+        // Сначала письмо: описание без письма при сбое не будет отправлено повторно
+        if let Err(e) = fs::remove_file(self.message_path(&entry.id)) {
+            warn!("Не удалось удалить письмо {}: {}", entry.id, e);
+        }
+        fs::remove_file(self.claim_path(&entry.id))?;
+        Ok(())
+    }
+
+    /// Отмечает неудачную попытку и откладывает следующую по `backoff`.
+    pub fn record_failure(
+        &self,
+        entry: &mut OutboxEntry,
+        error: &str,
+        now: NaiveDateTime,
+        settings: &OutboxSettings,
+    ) -> Result<()> {
+        entry.attempts += 1;
+        entry.last_error = Some(error.to_string());
+        entry.next_attempt = now + backoff(entry.attempts, settings);
+        self.save(entry)
+    }
+
+    /// Отмечает письмо, которое больше не повторяется автоматически.
+    pub fn mark_expired(&self, entry: &mut OutboxEntry) -> Result<()> {
+        entry.expired = true;
+        self.save(entry)
+    }
+
+    /// Сохраняет описание письма в очереди. Забранное письмо при этом возвращается в очередь.
+    fn save(&self, entry: &OutboxEntry) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.json.tmp", entry.id));
+        fs::write(&tmp, serde_json::to_string_pretty(entry)?)?;
+        fs::rename(&tmp, self.meta_path(&entry.id))
+            .wrap_err("Не удалось сохранить описание письма в очереди")?;
+        match fs::remove_file(self.claim_path(&entry.id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    fn message_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.eml"))
+    }
+
+    fn claim_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.sending"))
+    }
+}
+
+fn read_entry(path: &Path, id: String) -> Result<OutboxEntry> {
+    let mut entry: OutboxEntry = serde_json::from_str(&fs::read_to_string(path)?)?;
+    entry.id = id;
+    Ok(entry)
+}
+
+/// Пауза после `attempts` неудачных попыток: `retry_interval`, удваиваемый
+/// с каждой попыткой, но не больше `max_retry_interval`.
+pub fn backoff(attempts: u32, settings: &OutboxSettings) -> Duration {
+    let factor = 1i64 << attempts.saturating_sub(1).min(20);
+    let minutes = settings
+        .retry_interval
+        .saturating_mul(factor)
+        .min(settings.max_retry_interval);
+    Duration::minutes(minutes)
+}
+
+/// Ставит письмо в очередь и один раз пытается его отправить. Если почтовый сервер
+/// недоступен, письмо остается в очереди и отправляется фоновой задачей позже.
+pub async fn send(
+    services: &Services,
+    settings: &Settings,
+    subject: &str,
+    message: &str,
+    recipients: &[String],
+    delivery_id: Option<i64>,
+) -> Result<DeliveryStatus> {
+    let entry = {
+        let outbox = services.outbox.lock().await;
+        let entry = outbox.enqueue(
+            subject,
+            recipients,
+            message,
+            delivery_id,
+            Local::now().naive_local(),
+        )?;
+        outbox.claim(&entry.id)?
+    };
+    // Письмо уже забрала очередь другого процесса
+    let Some(mut entry) = entry else {
+        return Ok(DeliveryStatus::Queued);
+    };
+
+    let result = deliver(services, settings, &entry).await;
+    match result {
+        Ok(()) => Ok(DeliveryStatus::Sent),
+        Err(e) => {
+            note_failure(services, settings, &mut entry, &e).await?;
+            if entry.expired {
+                return Ok(DeliveryStatus::Queued);
+            }
+            warn!(
+                "Письмо \"{}\" оставлено в очереди, следующая попытка {}: {:#}",
+                subject,
+                entry.next_attempt.format("%d.%m.%y %H:%M"),
+                e
+            );
+            Ok(DeliveryStatus::Queued)
+        }
+    }
+}
+
+/// Отправляет письма, время повторной попытки которых подошло. При `force`
+/// отправляются все письма сразу; `only` ограничивает отправку одним письмом.
+/// Возвращает число отправленных писем и писем, оставшихся в очереди.
+pub async fn flush(
+    services: &Services,
+    settings: &Settings,
+    force: bool,
+    only: Option<&str>,
+) -> Result<(usize, usize)> {
+    let now = Local::now().naive_local();
+    let (mut sent, mut left) = (0, 0);
+    // Очередь блокируется только на время работы с файлами, не на время отправки
+    let entries = {
+        let outbox = services.outbox.lock().await;
+        outbox.release_stale()?;
+        outbox.entries()?
+    };
+
+    for entry in entries {
+        if only.is_some_and(|id| id != entry.id) {
+            continue;
+        }
+        let due = force || entry.next_attempt <= now;
+        let expired = !force && entry.is_expired(&settings.outbox, now);
+        if !due || (!force && entry.expired) {
+            left += 1;
+            continue;
+        }
+        let Some(mut entry) = services.outbox.lock().await.claim(&entry.id)? else {
+            // Письмо отправляет другая задача или процесс
+            left += 1;
+            continue;
+        };
+        if expired {
+            // Письмо остается в папке: удалить его можно только командой purge
+            warn!(
+                "Письмо \"{}\" не отправлено за {} ч. и больше не повторяется автоматически. \
+                 Отправить: lsr outbox retry {}, удалить: lsr outbox purge {}",
+                entry.subject,
+                settings.outbox.max_age_hours.unwrap_or_default(),
+                entry.id,
+                entry.id
+            );
+            services.outbox.lock().await.mark_expired(&mut entry)?;
+            left += 1;
+            continue;
+        }
+
+        let result = deliver(services, settings, &entry).await;
+        match result {
+            Ok(()) => {
+                info!("Письмо \"{}\" из очереди отправлено", entry.subject);
+                sent += 1;
+            }
+            Err(e) => {
+                note_failure(services, settings, &mut entry, &e).await?;
+                debug!("Письмо \"{}\" не отправлено: {:?}", entry.subject, e);
+                left += 1;
+            }
+        }
+    }
+    Ok((sent, left))
+}
+
+/// Удаляет письма из очереди без отправки. Удаленные письма не досылаются.
+pub fn purge(outbox: &Outbox, history: &History, only: Option<&str>) -> Result<usize> {
+    let mut removed = 0;
+    for entry in outbox.entries()? {
+        if only.is_some_and(|id| id != entry.id) {
+            continue;
+        }
+        // Письмо, которое сейчас отправляется, не удаляется
+        let Some(entry) = outbox.claim(&entry.id)? else {
+            continue;
+        };
+        outbox.remove(&entry)?;
+        if let Some(id) = entry.delivery_id {
+            history.set_status(id, DeliveryStatus::Purged, None)?;
+        }
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Фоновая задача: раз в минуту отправляет письма, время которых подошло.
+pub async fn run(services: Services, mut settings: Settings) {
+    loop {
+        if let Err(e) = settings.update() {
+            debug!("Не удалось обновить параметры очереди: {}", e);
+        }
+        match flush(&services, &settings, false, None).await {
+            Ok((sent, left)) if sent > 0 || left > 0 => {
+                info!("Очередь писем: отправлено {}, осталось {}", sent, left)
+            }
+            Ok(_) => {}
+            Err(e) => error!("Ошибка обработки очереди писем: {:?}", e),
+        }
+        sleep(TokioDuration::from_secs(FLUSH_INTERVAL)).await;
+    }
+}
+
+/// Записывает неудачную попытку, следующая откладывается от времени ошибки. Письмо,
+/// которое сервер отклонил окончательно, например из-за неверного адреса, больше
+/// не повторяется автоматически.
+async fn note_failure(
+    services: &Services,
+    settings: &Settings,
+    entry: &mut OutboxEntry,
+    error: &eyre::Report,
+) -> Result<()> {
+    if classify(error) == ErrorClass::Permanent {
+        warn!(
+            "Письмо \"{}\" отклонено и больше не повторяется автоматически: {:#}. \
+             Отправить: lsr outbox retry {}, удалить: lsr outbox purge {}",
+            entry.subject, error, entry.id, entry.id
+        );
+        entry.expired = true;
+    }
+    services.outbox.lock().await.record_failure(
+        entry,
+        &format!("{error:#}"),
+        Local::now().naive_local(),
+        &settings.outbox,
+    )
+}
+
+/// Одна попытка отправки письма из очереди.
+async fn deliver(services: &Services, settings: &Settings, entry: &OutboxEntry) -> Result<()> {
+    let message = services.outbox.lock().await.message(entry)?;
+    {
+        let mut mailer = services.mailer.lock().await;
+        mailer.reconnect(&settings.smtp).await?;
+        mailer.send_raw(&entry.to, &message).await?;
+    }
+    // Письмо уже принято сервером: если его не удалось удалить, оно остается забранным
+    // и повторно не отправляется
+    if let Err(e) = services.outbox.lock().await.remove(entry) {
+        error!(
+            "Письмо \"{}\" отправлено, но не удалено из очереди: {:?}",
+            entry.subject, e
+        );
+    }
+    set_delivery_status(services, entry, DeliveryStatus::Sent, None).await;
+    Ok(())
+}
+
+async fn set_delivery_status(
+    services: &Services,
+    entry: &OutboxEntry,
+    status: DeliveryStatus,
+    error: Option<&str>,
+) {
+    let Some(id) = entry.delivery_id else {
+        return;
+    };
+    if let Err(e) = services.history.lock().await.set_status(id, status, error) {
+        warn!("Не удалось обновить журнал отправок: {:?}", e);
+    }
+}
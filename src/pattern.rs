@@ -0,0 +1,78 @@
+use eyre::{eyre, Result};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::fmt;
+
+/// Шаблон имени станка или детали из настроек, без учета регистра:
+/// `"Mazak QTS350"` - точное совпадение, `"Rontek HTC420 *"` - маска с `*` и `?`,
+/// `"/^Rontek (HTC|VMC)/"` - регулярное выражение.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum NamePattern {
+    Exact(String),
+    Glob(String, Regex),
+    Regex(String, Regex),
+}
+
+impl NamePattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        if let Some(re) = pattern
+            .strip_prefix('/')
+            .and_then(|p| p.strip_suffix('/'))
+            .filter(|p| !p.is_empty())
+        {
+            return Ok(Self::Regex(pattern.to_string(), build_regex(re, pattern)?));
+        }
+        if pattern.contains(['*', '?']) {
+            let re = pattern
+                .split('*')
+                .map(|part| {
+                    part.split('?')
+                        .map(regex::escape)
+                        .collect::<Vec<_>>()
+                        .join(".")
+                })
+                .collect::<Vec<_>>()
+                .join(".*");
+            return Ok(Self::Glob(
+                pattern.to_string(),
+                build_regex(&format!("^{re}$"), pattern)?,
+            ));
+        }
+        Ok(Self::Exact(pattern.to_string()))
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        match self {
+            Self::Exact(p) => p.trim().to_lowercase() == name.trim().to_lowercase(),
+            Self::Glob(_, re) | Self::Regex(_, re) => re.is_match(name.trim()),
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        matches!(self, Self::Exact(_))
+    }
+}
+
+impl TryFrom<String> for NamePattern {
+    type Error = eyre::Error;
+
+    fn try_from(pattern: String) -> Result<Self> {
+        Self::new(&pattern)
+    }
+}
+
+impl fmt::Display for NamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exact(p) | Self::Glob(p, _) | Self::Regex(p, _) => write!(f, "{p}"),
+        }
+    }
+}
+
+fn build_regex(re: &str, pattern: &str) -> Result<Regex> {
+    RegexBuilder::new(re)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| eyre!("Неверный шаблон \"{}\": {}", pattern, e))
+}
@@ -1,124 +1,536 @@
-use crate::config::Settings;
-use crate::models::PartData;
-use crate::{
-    db::Database,
-    mailer::Mailer,
-    utils::{next_send_time, parse_time, retry, MAX_RETRY_ATTEMPTS, RETRY_DELAY},
-};
-use chrono::Local;
-use eyre::Result;
-use std::collections::HashMap;
+use crate::charts::{overrun_chart, timeline_chart, Chart};
+use crate::config::{MachineOrder, PartOrder, Settings};
+use crate::models::{DateRange, PartData, ReportRow};
+use crate::{history::DeliveryStatus, init::Services, norms, outbox, utils::retry};
+use eyre::{Context, Result};
+use minijinja::{context, AutoEscape, Environment};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FmtWrite;
-use std::sync::Arc;
-use tokio::sync::Mutex as TokioMutex;
-use tracing::info;
+use std::fs;
+use tracing::{debug, info, warn};
 
-pub fn generate_html_report(data: &[PartData], settings: &Settings) -> Result<String> {
-    let mut grouped_by_machine: HashMap<String, Vec<&PartData>> = HashMap::new();
+const TEMPLATE_NAME: &str = "report.html";
+const DEFAULT_TEMPLATE: &str = include_str!("../templates/report.html");
+const ALERT_TEMPLATE_NAME: &str = "alert.html";
+const ALERT_TEMPLATE: &str = include_str!("../templates/alert.html");
+
+/// Длительные наладки одного станка со сводными показателями.
+#[derive(Debug, Serialize)]
+pub struct MachineGroup {
+    pub name: String,
+    pub parts: Vec<PartContext>,
+    pub count: usize,
+    pub total_overrun: i64,
+    pub worst: Option<ReportRow>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartContext {
+    #[serde(flatten)]
+    pub row: ReportRow,
+    pub start: String,
+    pub end: String,
+    /// Ход наладки с перерывами, если включен `report.charts`
+    pub timeline: Option<Chart>,
+}
+
+/// HTML отчета и графики в нем, которые нужно вложить в письмо картинками.
+#[derive(Debug)]
+pub struct HtmlReport {
+    pub html: String,
+    pub charts: Vec<Chart>,
+}
+
+impl MachineGroup {
+    fn new(name: String, mut parts: Vec<PartContext>, order: PartOrder) -> Self {
+        match order {
+            PartOrder::StartTime => parts.sort_by_key(|p| p.row.start_setup_time),
+            PartOrder::Overrun => parts.sort_by(|a, b| {
+                b.row
+                    .overrun
+                    .cmp(&a.row.overrun)
+                    .then(a.row.start_setup_time.cmp(&b.row.start_setup_time))
+            }),
+        }
+        let worst = parts
+            .iter()
+            .map(|p| &p.row)
+            .reduce(|worst, row| {
+                if row.overrun > worst.overrun {
+                    row
+                } else {
+                    worst
+                }
+            })
+            .cloned();
+        Self {
+            count: parts.len(),
+            total_overrun: parts.iter().map(|p| p.row.overrun).sum(),
+            worst,
+            name,
+            parts,
+        }
+    }
+}
+
+/// Группирует наладки по станкам в порядке из `report.machine_order`,
+/// внутри станка - в порядке из `report.part_order`.
+pub fn group_by_machine(data: &[PartData], settings: &Settings) -> Vec<MachineGroup> {
+    let time_format = time_format(data);
+    let mut grouped_by_machine: HashMap<String, Vec<PartContext>> = HashMap::new();
 
     for part in data {
+        let row = ReportRow::new(part, settings);
+        let timeline = settings
+            .report
+            .charts
+            .then(|| timeline_chart(part, &settings.shifts, row.overrun > 0));
         grouped_by_machine
             .entry(part.machine.clone())
             .or_default()
-            .push(part);
+            .push(PartContext {
+                row,
+                start: part.start_setup_time.format(time_format).to_string(),
+                end: part.end_setup_time.format(time_format).to_string(),
+                timeline,
+            });
+    }
+
+    let mut groups: Vec<MachineGroup> = grouped_by_machine
+        .into_iter()
+        .map(|(name, parts)| MachineGroup::new(name, parts, settings.report.part_order))
+        .collect();
+
+    groups.sort_by(|a, b| {
+        a.name
+            .to_lowercase()
+            .cmp(&b.name.to_lowercase())
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    match settings.report.machine_order {
+        MachineOrder::Alphabetical => {}
+        MachineOrder::Workshop => {
+            let workshop_order = &settings.report.workshop_order;
+            groups.sort_by_key(|g| {
+                workshop_order
+                    .iter()
+                    .position(|p| p.is_match(&g.name))
+                    .unwrap_or(workshop_order.len())
+            });
+        }
+        MachineOrder::Overrun => groups.sort_by_key(|g| std::cmp::Reverse(g.total_overrun)),
+    }
+    groups
+}
+
+/// Если наладки попадают на разные дни, время выводится вместе с датой.
+fn time_format(data: &[PartData]) -> &'static str {
+    let multi_day = data
+        .iter()
+        .map(|p| p.start_setup_time.date())
+        .collect::<HashSet<_>>()
+        .len()
+        > 1;
+    if multi_day {
+        "%d.%m.%y %H:%M:%S"
+    } else {
+        "%H:%M:%S"
+    }
+}
+
+/// HTML отчета для браузера или файла: графики встроены в страницу как SVG.
+pub fn generate_html_report(data: &[PartData], settings: &Settings) -> Result<String> {
+    Ok(render(data, settings, true)?.html)
+}
+
+/// HTML отчета для письма вместе с графиками. Каждому графику присваивается свой `cid`,
+/// по которому письмо показывает его PNG из вложения.
+pub fn render_html_report(data: &[PartData], settings: &Settings) -> Result<HtmlReport> {
+    render(data, settings, false)
+}
+
+fn render(data: &[PartData], settings: &Settings, inline_svg: bool) -> Result<HtmlReport> {
+    let template = load_template(settings)?;
+    let mut machines = group_by_machine(data, settings);
+
+    let mut charts = Vec::new();
+    let overrun_chart = if settings.report.charts {
+        let totals: Vec<(&str, i64)> = machines
+            .iter()
+            .map(|m| (m.name.as_str(), m.total_overrun))
+            .collect();
+        overrun_chart(&totals).map(|mut chart| {
+            chart.cid = "chart-overrun".to_string();
+            charts.push(chart.clone());
+            chart
+        })
+    } else {
+        None
+    };
+    for (i, timeline) in machines
+        .iter_mut()
+        .flat_map(|m| m.parts.iter_mut())
+        .filter_map(|p| p.timeline.as_mut())
+        .enumerate()
+    {
+        timeline.cid = format!("chart-{}", i + 1);
+        charts.push(timeline.clone());
+    }
+
+    let mut env = Environment::new();
+    // Данные из базы вводятся операторами, поэтому экранирование включено для любого имени шаблона
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    env.add_template(TEMPLATE_NAME, &template)
+        .wrap_err("Ошибка разбора шаблона отчета")?;
+    let html = env
+        .get_template(TEMPLATE_NAME)?
+        .render(context! { machines, overrun_chart, inline_svg })
+        .wrap_err("Ошибка заполнения шаблона отчета")?;
+    Ok(HtmlReport { html, charts })
+}
+
+/// HTML оповещения о наладке, которая еще идет и уже превысила лимит.
+pub fn generate_alert_html(row: &ReportRow) -> Result<String> {
+    let part = PartContext {
+        row: row.clone(),
+        start: row.start_setup_time.format("%d.%m.%y %H:%M").to_string(),
+        end: String::new(),
+        timeline: None,
+    };
+
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    env.add_template(ALERT_TEMPLATE_NAME, ALERT_TEMPLATE)?;
+    env.get_template(ALERT_TEMPLATE_NAME)?
+        .render(context! { part })
+        .wrap_err("Ошибка заполнения шаблона оповещения")
+}
+
+pub fn generate_alert_text(row: &ReportRow) -> String {
+    format!(
+        "{}: наладка идёт дольше лимита\n\nДеталь: {}\nУстановка: {}\nМ/Л: {}\nОператор: {}\nСмена: {}\nНачало наладки: {}\nПрошло: {} мин.\nЧистое время: {} мин. ({})\nЛимит наладки: {} мин.{}\nКомментарий: {}\n",
+        row.machine,
+        row.part_name,
+        row.setup,
+        row.order,
+        row.operator,
+        if row.shift.is_empty() { "-" } else { &row.shift },
+        row.start_setup_time.format("%d.%m.%y %H:%M"),
+        row.gross_minutes,
+        row.setup_minutes,
+        row.time_policy,
+        row.limit,
+        norm_text(row),
+        row.operators_comment
+    )
+}
+
+/// Строка с нормой по истории для текстовых версий писем, пустая без нормы.
+fn norm_text(row: &ReportRow) -> String {
+    row.norm
+        .map(|norm| {
+            format!(
+                "\nНорма по истории: медиана {} мин., p90 {} мин. (наладок: {})",
+                norm.median, norm.p90, norm.count
+            )
+        })
+        .unwrap_or_default()
+}
+
+/// Шаблон из `report.template` или встроенный шаблон по умолчанию.
+pub fn load_template(settings: &Settings) -> Result<String> {
+    match &settings.report.template {
+        Some(path) => {
+            let path = settings.resolve_path(path);
+            fs::read_to_string(&path)
+                .wrap_err_with(|| format!("Не удалось прочитать шаблон {}", path.display()))
+        }
+        None => Ok(DEFAULT_TEMPLATE.to_string()),
     }
+}
+
+pub fn generate_text_report(data: &[PartData], settings: &Settings) -> Result<String> {
+    let machines = group_by_machine(data, settings);
 
-    let mut html = String::new();
-    writeln!(
-        html,
-        "<html><head><style>
-        body {{ font-family: Calibri, sans-serif; margin: 5px; }}
-        h3 {{ color: #003366; padding-bottom: 0px; }}
-        .part-block {{ border: 1px solid #ddd; padding: 6px; margin: 0px 2px 0px 2px; background-color: #f9f9f9; }}
-        .part-block p, .part-block pre {{ margin: 3px 0; font-family: Calibri, sans-serif; }}
-        pre {{ white-space: pre-wrap; word-wrap: break-word; }}
-        </style></head><body>"
-    )?;
-
-    for (machine, parts) in grouped_by_machine {
-        writeln!(html, "<h3>{}</h3>", machine)?;
-
-        for part in parts {
-            let setup_duration = part
-            .end_setup_time
-            .signed_duration_since(part.start_setup_time);
-            let breaks_minutes = part.breaks_between(true).num_minutes();
-            let setup_minutes = setup_duration.num_minutes() - breaks_minutes;
+    let mut text = String::new();
+    writeln!(text, "Сводка по станкам\n=================")?;
+    for machine in &machines {
+        let worst = machine
+            .worst
+            .as_ref()
+            .map(|w| format!("{}, +{} мин.", w.part_name, w.overrun))
+            .unwrap_or_default();
+        writeln!(
+            text,
+            "{}: наладок {}, превышение {} мин., худший случай: {}",
+            machine.name, machine.count, machine.total_overrun, worst
+        )?;
+    }
+    writeln!(text)?;
+
+    for machine in machines {
+        writeln!(
+            text,
+            "{}\n{}",
+            machine.name,
+            "=".repeat(machine.name.chars().count())
+        )?;
+
+        for part in machine.parts {
+            let row = part.row;
             writeln!(
-                html,
-                "<div class='part-block'>
-                    <p><strong>Деталь:</strong> {}</p>
-                    <p><strong>Установка:</strong> {}</p>
-                    <p><strong>М/Л:</strong> {}</p>
-                    <p><strong>Оператор:</strong> {}</p>
-                    <p><strong>Наладка:</strong> {} - {} ({} мин.)</p>
-                    <p><strong>Лимит наладки:</strong> {} мин.</p>
-                    <p><strong>Простои:</strong> {} мин.</p>
-                    <p><strong>Комментарий:</strong></p>
-                    <pre>{}</pre>
-                </div>",
-                part.part_name,
-                part.setup,
-                part.order,
-                part.operator,
-                part.start_setup_time.format("%H:%M:%S"),
-                part.end_setup_time.format("%H:%M:%S"),
-                setup_minutes,
-                settings.get_setup_limit(&part.machine),
-                part.downtimes,
-                part.operators_comment
+                text,
+                "Деталь: {}\nУстановка: {}\nМ/Л: {}\nОператор: {}\nСмена: {}\nНаладка: {} - {} ({} мин.)\nЧистое время: {} мин. ({})\nПерерывы: {} мин.\nЛимит наладки: {} мин.{}\nПростои: {} мин.\nКомментарий:\n{}\n",
+                row.part_name,
+                row.setup,
+                row.order,
+                row.operator,
+                if row.shift.is_empty() { "-" } else { &row.shift },
+                part.start,
+                part.end,
+                row.gross_minutes,
+                row.setup_minutes,
+                row.time_policy,
+                row.breaks_minutes,
+                row.limit,
+                norm_text(&row),
+                row.downtimes,
+                row.operators_comment
             )?;
         }
     }
+    Ok(text)
+}
 
-    writeln!(html, "</body></html>")?;
-    Ok(html)
+pub fn report_subject(period: &DateRange) -> String {
+    if period.is_single_day() {
+        format!("Ежедневный отчёт по длительным наладкам за {}", period)
+    } else {
+        format!("Отчёт по длительным наладкам за {}", period)
+    }
 }
 
-pub fn calc_delay(settings: &Settings) -> Result<u64> {
-    let now = Local::now();
-    let send_time = parse_time(&settings.report.send_time)?;
-    let next_run = next_send_time(now, send_time);
+/// Отчет для группы получателей: только наладки на станках группы.
+#[derive(Debug)]
+pub struct RoutedReport<'a> {
+    pub group: &'a str,
+    pub recipients: &'a [String],
+    pub data: Vec<PartData>,
+}
 
-    let duration_until_next = next_run - now;
-    info!(
-        "Следующий отчёт: {}, через {:02}:{:02}:{:02}",
-        next_run.format("%d.%m.%y %H:%M:%S"),
-        duration_until_next.num_hours(),
-        duration_until_next.num_minutes() % 60,
-        duration_until_next.num_seconds() % 60,
-    );
+/// Делит наладки по `[[recipient_groups]]`, станок может попасть в несколько групп.
+/// Наладки на станках вне групп уходят на `report.fallback_to`.
+/// Группы без наладок пропускаются.
+pub fn route_by_groups<'a>(data: &[PartData], settings: &'a Settings) -> Vec<RoutedReport<'a>> {
+    let mut reports: Vec<RoutedReport> = settings
+        .recipient_groups
+        .iter()
+        .map(|group| RoutedReport {
+            group: &group.name,
+            recipients: &group.to,
+            data: data
+                .iter()
+                .filter(|p| group.is_match(&p.machine))
+                .cloned()
+                .collect(),
+        })
+        .collect();
+    if !settings.recipient_groups.is_empty() {
+        reports.push(RoutedReport {
+            group: "прочие станки",
+            recipients: &settings.report.fallback_to,
+            data: data
+                .iter()
+                .filter(|p| {
+                    !settings
+                        .recipient_groups
+                        .iter()
+                        .any(|g| g.is_match(&p.machine))
+                })
+                .cloned()
+                .collect(),
+        });
+    }
+    reports.retain(|r| !r.data.is_empty());
+    reports
+}
 
-    let total_delay =
-        (duration_until_next.num_seconds() + settings.general.send_delay as i64) as u64;
+/// Отправляет отчет `name` за `period` через очередь писем и записывает итог в журнал.
+/// `recipients` получают полный отчет, группы из `[[recipient_groups]]` - отчеты
+/// по своим станкам.
+pub async fn send_report_with_retry(
+    services: &Services,
+    settings: &Settings,
+    name: &str,
+    period: &DateRange,
+    recipients: &[String],
+) -> Result<DeliveryStatus> {
+    let settings = &norms::with_norms(services, settings).await;
+    let subject = report_subject(period);
+    let data = retry(&settings.general.retry, || async {
+        let mut source = services.source.lock().await;
+        source.reconnect(settings).await?;
+        source.fetch_report_data(settings, period).await
+    })
+    .await;
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            record_delivery(
+                services,
+                name,
+                period,
+                &subject,
+                recipients,
+                &[],
+                DeliveryStatus::Failed,
+                Some(format!("{e:#}")),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+    let rows: Vec<ReportRow> = data.iter().map(|p| ReportRow::new(p, settings)).collect();
+    if rows.is_empty() {
+        info!("Длительных наладок по заданным критериям не было, отправлять нечего.");
+        record_delivery(
+            services,
+            name,
+            period,
+            &subject,
+            recipients,
+            &rows,
+            DeliveryStatus::Empty,
+            None,
+        )
+        .await;
+        return Ok(DeliveryStatus::Empty);
+    }
+
+    // Группы получают свои отчеты, даже если полный отчет отправить не удалось.
+    // При досылке полного отчета уже отправленные группам отчеты не повторяются
+    let status = send_part(
+        services, settings, name, period, &subject, &data, recipients, &rows,
+    )
+    .await;
 
-    Ok(total_delay)
+    for routed in route_by_groups(&data, settings) {
+        let name = format!("{name}/{}", routed.group);
+        match services.history.lock().await.was_delivered(&name, period) {
+            Ok(true) => {
+                debug!("Отчёт \"{}\" за {} уже отправлен", name, period);
+                continue;
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Не удалось проверить журнал отправок: {:?}", e),
+        }
+        let subject = format!("{subject} - {}", routed.group);
+        match send_part(
+            services,
+            settings,
+            &name,
+            period,
+            &subject,
+            &routed.data,
+            routed.recipients,
+            // Наладки уже записаны в журнал с полным отчетом
+            &[],
+        )
+        .await
+        {
+            Ok(status) => info!("Отчёт \"{}\": {:?}", name, status),
+            Err(e) => warn!("Отчёт \"{}\" не отправлен: {:?}", name, e),
+        }
+    }
+    status
 }
 
-pub async fn send_report_with_retry(
-    db: Arc<TokioMutex<Database>>,
-    mailer: Arc<TokioMutex<Mailer>>,
+/// Собирает письмо с наладками `data` и отправляет его через очередь,
+/// в журнал записываются наладки `rows`.
+#[allow(clippy::too_many_arguments)]
+async fn send_part(
+    services: &Services,
     settings: &Settings,
-) -> Result<()> {
-    retry(MAX_RETRY_ATTEMPTS, RETRY_DELAY, || {
-        let db = Arc::clone(&db);
-        let mailer = Arc::clone(&mailer);
-        let settings = settings.clone();
-        async move {
-            let mut db = db.lock().await;
-            db.reconnect(&settings).await?;
-            let data = db.fetch_report_data(&settings).await?;
-            let mut mailer = mailer.lock().await;
-            mailer.reconnect(&settings.smtp).await?;
-            mailer
-                .send_report(
-                    "Ежедневный отчёт по длительным наладкам",
-                    &data,
-                    "Уведомлятель",
-                    &settings,
-                )
-                .await
+    name: &str,
+    period: &DateRange,
+    subject: &str,
+    data: &[PartData],
+    recipients: &[String],
+    rows: &[ReportRow],
+) -> Result<DeliveryStatus> {
+    let message = services.mailer.lock().await.compose_report(
+        subject,
+        data,
+        "Уведомлятель",
+        settings,
+        recipients,
+    );
+    let message = match message {
+        Ok(message) => message,
+        Err(e) => {
+            // Наладки записываются и при неудаче, чтобы их можно было найти в журнале
+            record_delivery(
+                services,
+                name,
+                period,
+                subject,
+                recipients,
+                rows,
+                DeliveryStatus::Failed,
+                Some(format!("{e:#}")),
+            )
+            .await;
+            return Err(e);
         }
-    })
+    };
+    let delivery_id = record_delivery(
+        services,
+        name,
+        period,
+        subject,
+        recipients,
+        rows,
+        DeliveryStatus::Queued,
+        None,
+    )
+    .await;
+    outbox::send(
+        services,
+        settings,
+        subject,
+        &message,
+        recipients,
+        delivery_id,
+    )
     .await
 }
+
+/// Записывает отправку в журнал. Ошибка записи не мешает отправке и только пишется в лог.
+#[allow(clippy::too_many_arguments)]
+pub async fn record_delivery(
+    services: &Services,
+    name: &str,
+    period: &DateRange,
+    subject: &str,
+    recipients: &[String],
+    rows: &[ReportRow],
+    status: DeliveryStatus,
+    error: Option<String>,
+) -> Option<i64> {
+    match services.history.lock().await.record(
+        name,
+        period,
+        subject,
+        recipients,
+        rows,
+        status,
+        error.as_deref(),
+    ) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!("Не удалось записать отправку в журнал: {:?}", e);
+            None
+        }
+    }
+}
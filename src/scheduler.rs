@@ -0,0 +1,281 @@
+use crate::config::{ReportKind, ScheduleSettings, Settings};
+use crate::history::{DeliveryStatus, History};
+use crate::init::Services;
+use crate::models::DateRange;
+use crate::reports::send_report_with_retry;
+use crate::trends::send_trend_with_retry;
+use chrono::{DateTime, Duration, Local};
+use croner::Cron;
+use eyre::{eyre, Result};
+use serde::Deserialize;
+use std::fmt;
+use tokio::time::{sleep, Duration as TokioDuration};
+use tracing::{debug, error, info, warn};
+
+/// Пауза между повторными попытками досылки, если пропущенные отчеты остались.
+pub const CATCH_UP_RETRY_DELAY: u64 = 30 * 60;
+
+/// Cron-выражение из пяти полей: минута, час, день месяца, месяц, день недели.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CronSchedule(Cron);
+
+/// Ближайший запуск: время и все расписания, которые на него приходятся.
+#[derive(Debug)]
+pub struct NextRun {
+    pub at: DateTime<Local>,
+    pub schedules: Vec<ScheduleSettings>,
+}
+
+impl CronSchedule {
+    pub fn new(expression: &str) -> Result<Self> {
+        Cron::new(expression)
+            .parse()
+            .map(Self)
+            .map_err(|e| eyre!("Неверное cron-выражение \"{expression}\": {e}"))
+    }
+
+    /// Первое срабатывание строго после `after`.
+    pub fn next_after(&self, after: &DateTime<Local>) -> Result<DateTime<Local>> {
+        self.0
+            .find_next_occurrence(after, false)
+            .map_err(|e| eyre!("Не удалось найти следующее срабатывание \"{self}\": {e}"))
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        Self::new(&value)
+    }
+}
+
+impl fmt::Display for CronSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_str())
+    }
+}
+
+pub fn next_run(schedules: &[ScheduleSettings], now: DateTime<Local>) -> Result<NextRun> {
+    let mut next: Option<NextRun> = None;
+    for schedule in schedules {
+        let at = schedule.cron.next_after(&now)?;
+        match &mut next {
+            Some(run) if run.at == at => run.schedules.push(schedule.clone()),
+            Some(run) if run.at < at => {}
+            _ => {
+                next = Some(NextRun {
+                    at,
+                    schedules: vec![schedule.clone()],
+                })
+            }
+        }
+    }
+    next.ok_or_else(|| eyre!("Не задано ни одного расписания"))
+}
+
+/// Ожидание в секундах до ближайшего запуска с учетом `general.send_delay`.
+pub fn calc_delay(settings: &Settings) -> Result<(u64, Vec<ScheduleSettings>)> {
+    let now = Local::now();
+    let next = next_run(&settings.schedules, now)?;
+
+    let duration_until_next = next.at - now;
+    info!(
+        "Следующий отчёт: {} ({}), через {:02}:{:02}:{:02}",
+        next.at.format("%d.%m.%y %H:%M:%S"),
+        next.schedules
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        duration_until_next.num_hours(),
+        duration_until_next.num_minutes() % 60,
+        duration_until_next.num_seconds() % 60,
+    );
+
+    let total_delay =
+        (duration_until_next.num_seconds() + settings.general.send_delay as i64).max(0) as u64;
+
+    Ok((total_delay, next.schedules))
+}
+
+/// Основной цикл приложения и службы: досылает пропущенные отчеты, ждет ближайшего
+/// расписания и отправляет его отчеты. Настройки перечитываются после каждого ожидания.
+/// Завершается, только если время следующего запуска вычислить нельзя.
+pub async fn run_loop(services: Services, mut settings: Settings) {
+    loop {
+        let pending = catch_up(&services, &settings).await;
+        let (secs, schedules) = match calc_delay(&settings) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Не удалость вычислить время ожидания.\n{}", e);
+                break;
+            }
+        };
+        // Пока остаются недосланные отчеты, повторяем досылку не дожидаясь расписания
+        let retry_catch_up = pending > 0 && secs > CATCH_UP_RETRY_DELAY;
+        if retry_catch_up {
+            info!(
+                "Недосланных отчётов: {}, повтор через {} мин.",
+                pending,
+                CATCH_UP_RETRY_DELAY / 60
+            );
+        }
+        sleep(TokioDuration::from_secs(if retry_catch_up {
+            CATCH_UP_RETRY_DELAY
+        } else {
+            secs
+        }))
+        .await;
+
+        if let Err(e) = settings.update() {
+            warn!(
+                "Не удалось обновить параметры приложения: {}\nИспользуются предыдущие настройки.",
+                e
+            );
+            debug!("Текущие параметры приложения:\n{}", settings);
+        } else {
+            debug!("Параметры приложения успешно обновлены:\n{}", settings);
+        }
+
+        if retry_catch_up {
+            continue;
+        }
+
+        run_due(&services, &settings, &schedules).await;
+    }
+}
+
+/// Отправляет отчеты сработавших расписаний и пишет итог в лог.
+pub async fn run_due(services: &Services, settings: &Settings, schedules: &[ScheduleSettings]) {
+    for schedule in schedules {
+        match run_schedule(services, settings, schedule).await {
+            Ok(Some(DeliveryStatus::Queued)) => warn!(
+                "Отчёт \"{}\" поставлен в очередь и будет отправлен позже",
+                schedule.name
+            ),
+            Ok(Some(_)) => info!("Отчёт \"{}\" успешно отправлен", schedule.name),
+            Ok(None) => {}
+            Err(e) => error!(
+                "Все попытки отправки отчета \"{}\" исчерпаны: {:?}",
+                schedule.name, e
+            ),
+        }
+    }
+}
+
+/// Отправляет отчет по расписанию, если он еще не был доставлен за этот период.
+/// `None` означает, что отчет пропущен.
+async fn run_schedule(
+    services: &Services,
+    settings: &Settings,
+    schedule: &ScheduleSettings,
+) -> Result<Option<DeliveryStatus>> {
+    let period = schedule.period.range(Local::now().date_naive());
+    if services
+        .history
+        .lock()
+        .await
+        .was_delivered(&schedule.name, &period)?
+    {
+        info!(
+            "Отчёт \"{}\" за {} уже был отправлен, пропуск",
+            schedule.name, period
+        );
+        return Ok(None);
+    }
+    send_schedule(services, settings, schedule, &period)
+        .await
+        .map(Some)
+}
+
+/// Периоды отчета `schedule`, срабатывания которого за последние `window_days` дней
+/// прошли без успешной отправки. Учитываются только периоды не раньше первой записи
+/// журнала по этому расписанию, чтобы после установки не рассылать старые отчеты.
+pub fn missed_periods(
+    schedule: &ScheduleSettings,
+    history: &History,
+    now: DateTime<Local>,
+    window_days: i64,
+) -> Result<Vec<DateRange>> {
+    let Some(first) = history.first_period(&schedule.name)? else {
+        return Ok(Vec::new());
+    };
+
+    let mut periods = Vec::new();
+    let mut after = now - Duration::days(window_days);
+    loop {
+        let at = schedule.cron.next_after(&after)?;
+        if at > now {
+            break;
+        }
+        let period = schedule.period.range(at.date_naive());
+        if period.from >= first
+            && !periods.contains(&period)
+            && !history.was_delivered(&schedule.name, &period)?
+        {
+            periods.push(period);
+        }
+        after = at;
+    }
+    Ok(periods)
+}
+
+/// Досылает пропущенные отчеты за окно `report.catch_up_days`, начиная с самых старых.
+/// Возвращает число периодов, которые отправить так и не удалось.
+pub async fn catch_up(services: &Services, settings: &Settings) -> usize {
+    if settings.report.catch_up_days <= 0 {
+        return 0;
+    }
+    let now = Local::now();
+    let mut pending = 0;
+    for schedule in &settings.schedules {
+        let missed = {
+            let history = services.history.lock().await;
+            missed_periods(schedule, &history, now, settings.report.catch_up_days)
+        };
+        let missed = match missed {
+            Ok(missed) => missed,
+            Err(e) => {
+                error!(
+                    "Не удалось определить пропущенные отчёты \"{}\": {:?}",
+                    schedule.name, e
+                );
+                continue;
+            }
+        };
+        for period in missed {
+            info!(
+                "Досылка пропущенного отчёта \"{}\" за {}",
+                schedule.name, period
+            );
+            if let Err(e) = send_schedule(services, settings, schedule, &period).await {
+                warn!(
+                    "Пропущенный отчёт \"{}\" за {} не отправлен: {:?}",
+                    schedule.name, period, e
+                );
+                pending += 1;
+            }
+        }
+    }
+    pending
+}
+
+async fn send_schedule(
+    services: &Services,
+    settings: &Settings,
+    schedule: &ScheduleSettings,
+    period: &DateRange,
+) -> Result<DeliveryStatus> {
+    let recipients = schedule.recipients(&settings.smtp);
+    info!("Расписание \"{}\": отчёт за {}", schedule.name, period);
+    match schedule.kind {
+        ReportKind::Setups => {
+            send_report_with_retry(services, settings, &schedule.name, period, recipients).await
+        }
+        ReportKind::Trend => {
+            send_trend_with_retry(services, settings, &schedule.name, period, recipients).await
+        }
+    }
+}
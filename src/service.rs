@@ -1,18 +1,10 @@
-mod config;
-mod db;
-mod init;
-mod logging;
-mod mailer;
-mod models;
-mod reports;
-mod tests;
-mod utils;
-
-use config::Settings;
 use eyre::Result;
-use init::{init_db, init_mailer};
-use logging::{init_logger, LoggerLayers};
-use reports::{calc_delay, send_report_with_retry};
+use long_setups_reporter::config::Settings;
+use long_setups_reporter::init::init_services;
+use long_setups_reporter::logging::{init_logger, LoggerLayers};
+use long_setups_reporter::scheduler::run_loop;
+use long_setups_reporter::watcher::watch_if_enabled;
+use long_setups_reporter::{http, outbox};
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::sync::{
@@ -21,8 +13,8 @@ use std::sync::{
 };
 use std::time::Duration;
 use tokio::runtime::Runtime;
-use tokio::time::{sleep, Duration as TokioDuration};
-use tracing::{debug, error, info, warn};
+use tokio::time::Duration as TokioDuration;
+use tracing::{error, info};
 use windows_service::define_windows_service;
 use windows_service::service::{
     ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
@@ -73,12 +65,15 @@ fn service_main(_arguments: Vec<std::ffi::OsString>) {
 fn run_service() -> Result<()> {
     let runtime = Runtime::new()?;
     let _ = runtime.block_on(async {
-        let mut settings = Settings::new()?;
+        let settings = Settings::new()?;
 
         let _guard = init_logger(&settings, LoggerLayers::Both);
         info!("Приложение запущено");
-        let db = init_db(&settings).await?;
-        let mailer = init_mailer(&settings).await?;
+        let services = init_services(&settings).await?;
+        let alerts_task = watch_if_enabled(services.clone(), settings.clone());
+        let outbox_task = outbox::run(services.clone(), settings.clone());
+        let http_task = http::serve_if_enabled(services.clone(), settings.clone());
+        let main_task = run_loop(services, settings);
         let running = Arc::new(AtomicBool::new(true));
         let running_clone = Arc::clone(&running);
 
@@ -107,35 +102,6 @@ fn run_service() -> Result<()> {
         info!("Установка статуса Running");
         status_handle.set_service_status(next_status)?;
 
-        let main_task = async {
-            loop {
-                let secs = match calc_delay(&settings) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("Не удалость вычислить время ожидания.\n{}", e);
-                        break;
-                    }
-                };
-                debug!("{secs}");
-                sleep(TokioDuration::from_secs(secs)).await;
-
-                if let Err(e) = settings.update() {
-                    warn!("Не удалось обновить параметры приложения: {}\nИспользуются предыдущие настройки.", e);
-                    debug!("Текущие параметры приложения:\n{}", settings);
-                } else {
-                    debug!("Параметры приложения успешно обновлены:\n{}", settings);
-                }
-
-                if let Err(e) =
-                    send_report_with_retry(Arc::clone(&db), Arc::clone(&mailer), &settings).await
-                {
-                    error!("Все попытки отправки отчета исчерпаны: {:?}", e);
-                } else {
-                    info!("Отчёт успешно отправлен");
-                }
-            }
-        };
-
         tokio::select! {
             _ = async {
                 while running.load(Ordering::SeqCst) {
@@ -144,6 +110,9 @@ fn run_service() -> Result<()> {
                 info!("Сигнал завершения службы получен.");
             } => { }
             _ = main_task => {}
+            _ = alerts_task => {}
+            _ = outbox_task => {}
+            _ = http_task => {}
         }
 
         info!("Остановка службы");
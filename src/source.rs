@@ -0,0 +1,155 @@
+use crate::config::{Settings, SourceKind, SourceSettings};
+use crate::csv_source::CsvSource;
+use crate::db::Database;
+use crate::models::{merge_split_setups, DateRange, PartData};
+use crate::sqlite_source::SqliteSource;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use eyre::Result;
+use tracing::debug;
+
+/// Источник данных о наладках: SQL Server, выгрузка CSV или файл SQLite.
+#[async_trait]
+pub trait SetupSource: Send {
+    /// Проверяет, что источник доступен.
+    async fn ping(&mut self) -> Result<()>;
+
+    /// Переподключается перед запросом, если у источника есть соединение.
+    async fn reconnect(&mut self, settings: &Settings) -> Result<()>;
+
+    /// Завершенные наладки со сменной датой в пределах `period`.
+    async fn fetch_setups(&mut self, period: &DateRange) -> Result<Vec<PartData>>;
+
+    /// Наладки, начатые не раньше `since` и еще не завершенные запуском обработки.
+    /// Окончанием таких наладок считается `now`.
+    async fn fetch_running_setups(
+        &mut self,
+        since: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<Vec<PartData>>;
+
+    /// Завершенные наладки за `period`. Наладки, записанные несколькими строками,
+    /// при `report.merge_split_setups` собираются в одну.
+    async fn fetch_merged_setups(
+        &mut self,
+        settings: &Settings,
+        period: &DateRange,
+    ) -> Result<Vec<PartData>> {
+        let parts = self.fetch_setups(period).await?;
+        Ok(if settings.report.merge_split_setups {
+            merge_split_setups(parts)
+        } else {
+            parts
+        })
+    }
+
+    /// Наладки за `period`, превысившие свой лимит.
+    async fn fetch_report_data(
+        &mut self,
+        settings: &Settings,
+        period: &DateRange,
+    ) -> Result<Vec<PartData>> {
+        let parts = self.fetch_merged_setups(settings, period).await?;
+        Ok(long_setups(parts, settings))
+    }
+}
+
+/// Открывает источник, выбранный в `source.kind`.
+pub async fn open(settings: &Settings) -> Result<Box<dyn SetupSource>> {
+    Ok(match settings.source.kind {
+        SourceKind::SqlServer => Box::new(Database::new(settings).await?),
+        SourceKind::Csv => Box::new(CsvSource::new(settings)?),
+        SourceKind::Sqlite => Box::new(SqliteSource::new(settings)?),
+    })
+}
+
+/// Столбцы `parts`, нужные для завершенных наладок.
+pub const COMPLETED_FIELDS: [&str; 9] = [
+    "PartName",
+    "Setup",
+    "Order",
+    "Machine",
+    "Operator",
+    "StartSetupTime",
+    "StartMachiningTime",
+    "SetupDowntimes",
+    "OperatorComment",
+];
+
+/// Столбцы `parts`, нужные для незавершенных наладок.
+pub const RUNNING_FIELDS: [&str; 8] = [
+    "PartName",
+    "Setup",
+    "Order",
+    "Machine",
+    "Operator",
+    "StartSetupTime",
+    "SetupDowntimes",
+    "OperatorComment",
+];
+
+/// Имя таблицы или столбца в квадратных скобках, понятное и SQL Server, и SQLite.
+/// Имя таблицы со схемой `dbo.parts` экранируется по частям.
+pub fn quote_ident(name: &str) -> String {
+    name.split('.')
+        .map(|part| format!("[{}]", part.trim().replace(']', "]]")))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Запрос к `source.table`: столбцы `fields` таблицы `parts` выбираются из столбцов
+/// источника по `source.columns` под своими именами, к условию `condition`
+/// добавляются `source.filters`. В `condition` и `order_by` столбцы источника
+/// подставляются вместо `{Имя}` столбца `parts`.
+pub fn select_query(
+    source: &SourceSettings,
+    fields: &[&str],
+    condition: &str,
+    order_by: &str,
+) -> String {
+    let columns = source.columns.all();
+    let substitute = |sql: &str| {
+        columns.iter().fold(sql.to_string(), |sql, column| {
+            sql.replace(&format!("{{{}}}", column.field), &quote_ident(column.name))
+        })
+    };
+    let select = columns
+        .iter()
+        .filter(|column| fields.contains(&column.field))
+        .map(|column| {
+            format!(
+                "{} AS {}",
+                quote_ident(column.name),
+                quote_ident(column.field)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let conditions = std::iter::once(substitute(condition))
+        .chain(source.filters.iter().map(|f| format!("({f})")))
+        .collect::<Vec<_>>()
+        .join(" AND ");
+    format!(
+        "SELECT {select} FROM {} WHERE {conditions} ORDER BY {}",
+        quote_ident(&source.table),
+        substitute(order_by)
+    )
+}
+
+/// Оставляет только наладки дольше их лимита.
+pub fn long_setups(parts: Vec<PartData>, settings: &Settings) -> Vec<PartData> {
+    parts
+        .into_iter()
+        .filter(|part| {
+            let actual_minutes = part.setup_minutes(settings);
+            let limit = settings.get_setup_limit(&part.machine, &part.part_name, part.setup);
+            if actual_minutes > limit {
+                debug!(
+                    "Превышение лимита наладки:\nСтанок: {}\n{}\nЛимит: {}\nФактическое время: {}",
+                    part.machine, part.part_name, limit, actual_minutes
+                );
+            }
+            actual_minutes > limit
+        })
+        .collect()
+}
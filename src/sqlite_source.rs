@@ -0,0 +1,119 @@
+use crate::config::{Settings, SourceSettings};
+use crate::models::{DateRange, PartData};
+use crate::source::{select_query, SetupSource, COMPLETED_FIELDS, RUNNING_FIELDS};
+use crate::utils::PermanentError;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use eyre::{Context, Result};
+use rusqlite::{params, Connection, OpenFlags, Row};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Наладки из таблицы `source.table` в файле SQLite, столбцы - по `source.columns`.
+/// Даты хранятся текстом в формате `YYYY-MM-DD HH:MM:SS`.
+pub struct SqliteSource {
+    path: PathBuf,
+    conn: Connection,
+    source: SourceSettings,
+}
+
+impl SqliteSource {
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let path = settings
+            .source
+            .path
+            .clone()
+            .ok_or_else(|| PermanentError("Не задан source.path".to_string()))?;
+        let conn = Self::open(&path)?;
+        Ok(Self {
+            path,
+            conn,
+            source: settings.source.clone(),
+        })
+    }
+
+    fn open(path: &Path) -> Result<Connection> {
+        Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .wrap_err_with(|| format!("Не удалось открыть {}", path.display()))
+    }
+
+    fn query(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+        parse: impl Fn(&Row) -> rusqlite::Result<PartData>,
+    ) -> Result<Vec<PartData>> {
+        let mut stmt = self
+            .conn
+            .prepare(sql)
+            .wrap_err("Ошибка выполнения запроса")?;
+        let mut parts = Vec::new();
+        for row in stmt.query_map(params, |row| Ok(parse(row)))? {
+            match row? {
+                Ok(part) => parts.push(part),
+                Err(e) => debug!("Ошибка при разборе строки данных: {:?}", e),
+            }
+        }
+        Ok(parts)
+    }
+}
+
+fn part_from_row(row: &Row, end_setup_time: NaiveDateTime) -> rusqlite::Result<PartData> {
+    Ok(PartData {
+        part_name: row.get("PartName")?,
+        setup: row.get("Setup")?,
+        order: row.get("Order")?,
+        machine: row.get("Machine")?,
+        operator: row.get("Operator")?,
+        start_setup_time: row.get("StartSetupTime")?,
+        end_setup_time,
+        operators_comment: row
+            .get::<_, Option<String>>("OperatorComment")?
+            .unwrap_or_default(),
+        downtimes: row.get::<_, Option<f64>>("SetupDowntimes")?.unwrap_or(0.0),
+        gaps: Vec::new(),
+    })
+}
+
+#[async_trait]
+impl SetupSource for SqliteSource {
+    async fn ping(&mut self) -> Result<()> {
+        let sql = select_query(&self.source, &COMPLETED_FIELDS, "1 = 0", "1");
+        self.conn
+            .prepare(&sql)
+            .map(|_| ())
+            .wrap_err_with(|| format!("Таблица {} не подходит", self.source.table))
+    }
+
+    async fn reconnect(&mut self, settings: &Settings) -> Result<()> {
+        self.conn = Self::open(&self.path)?;
+        self.source = settings.source.clone();
+        Ok(())
+    }
+
+    async fn fetch_setups(&mut self, period: &DateRange) -> Result<Vec<PartData>> {
+        let sql = select_query(
+            &self.source,
+            &COMPLETED_FIELDS,
+            "date({ShiftDate}) BETWEEN ?1 AND ?2 AND {StartMachiningTime} IS NOT NULL",
+            "{StartSetupTime} DESC",
+        );
+        self.query(&sql, params![period.from, period.to], |row| {
+            part_from_row(row, row.get("StartMachiningTime")?)
+        })
+    }
+
+    async fn fetch_running_setups(
+        &mut self,
+        since: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Result<Vec<PartData>> {
+        let sql = select_query(
+            &self.source,
+            &RUNNING_FIELDS,
+            "{StartSetupTime} >= ?1 AND {StartMachiningTime} IS NULL",
+            "{StartSetupTime}",
+        );
+        self.query(&sql, params![since], |row| part_from_row(row, now))
+    }
+}
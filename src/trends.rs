@@ -0,0 +1,368 @@
+use crate::config::Settings;
+use crate::models::{DateRange, PartData};
+use crate::reports::record_delivery;
+use crate::{history::DeliveryStatus, init::Services, norms, outbox, utils::retry};
+use eyre::{Context, Result};
+use minijinja::{context, AutoEscape, Environment};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write as FmtWrite;
+use tracing::info;
+
+const TREND_TEMPLATE_NAME: &str = "trend.html";
+const TREND_TEMPLATE: &str = include_str!("../templates/trend.html");
+
+/// Сколько деталей и операторов выводить в рейтингах по превышению.
+const TOP_COUNT: usize = 5;
+
+/// Показатели наладок станка или всего цеха за период.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct TrendStats {
+    /// Все завершенные наладки
+    pub setups: usize,
+    /// Наладки дольше лимита
+    pub long_setups: usize,
+    pub total_overrun: i64,
+    /// Среднее превышение одной длительной наладки, мин.
+    pub avg_overrun: f64,
+    /// Доля длительных наладок, %
+    pub long_share: f64,
+}
+
+/// Изменение показателей относительно предыдущего периода, со знаком.
+#[derive(Debug, Serialize)]
+pub struct TrendChange {
+    pub long_setups: String,
+    pub total_overrun: String,
+    pub avg_overrun: String,
+    pub long_share: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MachineTrend {
+    pub name: String,
+    pub current: TrendStats,
+    pub previous: TrendStats,
+    pub change: TrendChange,
+}
+
+/// Деталь или оператор в рейтинге по суммарному превышению.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct TopEntry {
+    pub name: String,
+    pub long_setups: usize,
+    pub total_overrun: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrendReport {
+    pub period: String,
+    pub previous_period: String,
+    pub total: MachineTrend,
+    pub machines: Vec<MachineTrend>,
+    pub top_parts: Vec<TopEntry>,
+    pub top_operators: Vec<TopEntry>,
+}
+
+impl TrendStats {
+    /// `overruns` - превышение каждой наладки, 0 для наладок в пределах лимита.
+    fn new(overruns: &[i64]) -> Self {
+        let setups = overruns.len();
+        let long_setups = overruns.iter().filter(|&&o| o > 0).count();
+        let total_overrun = overruns.iter().sum();
+        Self {
+            setups,
+            long_setups,
+            total_overrun,
+            avg_overrun: round1(ratio(total_overrun as f64, long_setups)),
+            long_share: round1(ratio(long_setups as f64 * 100.0, setups)),
+        }
+    }
+}
+
+impl TrendChange {
+    fn new(current: &TrendStats, previous: &TrendStats) -> Self {
+        Self {
+            long_setups: signed(current.long_setups as f64 - previous.long_setups as f64),
+            total_overrun: signed((current.total_overrun - previous.total_overrun) as f64),
+            avg_overrun: signed(current.avg_overrun - previous.avg_overrun),
+            long_share: signed(current.long_share - previous.long_share),
+        }
+    }
+}
+
+impl MachineTrend {
+    fn new(name: String, current: &[i64], previous: &[i64]) -> Self {
+        let current = TrendStats::new(current);
+        let previous = TrendStats::new(previous);
+        Self {
+            name,
+            change: TrendChange::new(&current, &previous),
+            current,
+            previous,
+        }
+    }
+}
+
+fn ratio(value: f64, count: usize) -> f64 {
+    if count == 0 {
+        0.0
+    } else {
+        value / count as f64
+    }
+}
+
+fn round1(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+fn signed(value: f64) -> String {
+    let value = round1(value);
+    if value > 0.0 {
+        format!("+{value}")
+    } else if value < 0.0 {
+        format!("−{}", -value)
+    } else {
+        "0".to_string()
+    }
+}
+
+/// Превышение лимита каждой наладки по станкам. Лимит и время наладки считаются
+/// так же, как в ежедневном отчете.
+fn overruns_by_machine(parts: &[PartData], settings: &Settings) -> HashMap<String, Vec<i64>> {
+    let mut machines: HashMap<String, Vec<i64>> = HashMap::new();
+    for part in parts {
+        let limit = settings.get_setup_limit(&part.machine, &part.part_name, part.setup);
+        let overrun = (part.setup_minutes(settings) - limit).max(0);
+        machines
+            .entry(part.machine.clone())
+            .or_default()
+            .push(overrun);
+    }
+    machines
+}
+
+/// Рейтинг по суммарному превышению. Превышение наладки, которую вели несколько
+/// операторов, засчитывается каждому из них.
+fn top_by<'a>(
+    parts: &'a [PartData],
+    settings: &Settings,
+    keys: impl Fn(&'a PartData) -> Vec<&'a str>,
+) -> Vec<TopEntry> {
+    let mut totals: HashMap<&str, (usize, i64)> = HashMap::new();
+    for part in parts {
+        let limit = settings.get_setup_limit(&part.machine, &part.part_name, part.setup);
+        let overrun = part.setup_minutes(settings) - limit;
+        if overrun <= 0 {
+            continue;
+        }
+        for key in keys(part) {
+            let entry = totals.entry(key).or_default();
+            entry.0 += 1;
+            entry.1 += overrun;
+        }
+    }
+    let mut top: Vec<TopEntry> = totals
+        .into_iter()
+        .map(|(name, (long_setups, total_overrun))| TopEntry {
+            name: name.to_string(),
+            long_setups,
+            total_overrun,
+        })
+        .collect();
+    top.sort_by(|a, b| {
+        b.total_overrun
+            .cmp(&a.total_overrun)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    top.truncate(TOP_COUNT);
+    top
+}
+
+/// Сводка за `period` по всем завершенным наладкам `current` в сравнении
+/// с наладками `previous` за предыдущий период.
+pub fn build_trend(
+    current: &[PartData],
+    previous: &[PartData],
+    period: &DateRange,
+    settings: &Settings,
+) -> TrendReport {
+    let current_by_machine = overruns_by_machine(current, settings);
+    let previous_by_machine = overruns_by_machine(previous, settings);
+
+    let names: BTreeSet<&String> = current_by_machine
+        .keys()
+        .chain(previous_by_machine.keys())
+        .collect();
+    let mut machines: Vec<MachineTrend> = names
+        .into_iter()
+        .map(|name| {
+            MachineTrend::new(
+                name.clone(),
+                current_by_machine.get(name).map_or(&[], Vec::as_slice),
+                previous_by_machine.get(name).map_or(&[], Vec::as_slice),
+            )
+        })
+        .collect();
+    // Стабильная сортировка: при равном превышении станки остаются по алфавиту
+    machines.sort_by_key(|m| std::cmp::Reverse(m.current.total_overrun));
+
+    let all_current: Vec<i64> = current_by_machine.into_values().flatten().collect();
+    let all_previous: Vec<i64> = previous_by_machine.into_values().flatten().collect();
+
+    TrendReport {
+        period: period.to_string(),
+        previous_period: period.previous().to_string(),
+        total: MachineTrend::new("Итого".to_string(), &all_current, &all_previous),
+        machines,
+        top_parts: top_by(current, settings, |p| vec![p.part_name.as_str()]),
+        top_operators: top_by(current, settings, |p| {
+            p.operator.split(", ").filter(|o| !o.is_empty()).collect()
+        }),
+    }
+}
+
+pub fn generate_trend_html(report: &TrendReport) -> Result<String> {
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    env.add_template(TREND_TEMPLATE_NAME, TREND_TEMPLATE)?;
+    env.get_template(TREND_TEMPLATE_NAME)?
+        .render(context! { report })
+        .wrap_err("Ошибка заполнения шаблона сводки")
+}
+
+pub fn generate_trend_text(report: &TrendReport) -> Result<String> {
+    let mut text = String::new();
+    writeln!(
+        text,
+        "Сводка по длительным наладкам за {}\nСравнение с {}\n",
+        report.period, report.previous_period
+    )?;
+    for machine in std::iter::once(&report.total).chain(&report.machines) {
+        let (current, change) = (&machine.current, &machine.change);
+        writeln!(
+            text,
+            "{}: наладок {}, длительных {} ({}) - {}% ({} п.п.), превышение {} мин. ({}), в среднем {} мин. ({})",
+            machine.name,
+            current.setups,
+            current.long_setups,
+            change.long_setups,
+            current.long_share,
+            change.long_share,
+            current.total_overrun,
+            change.total_overrun,
+            current.avg_overrun,
+            change.avg_overrun
+        )?;
+    }
+    for (title, top) in [
+        ("Детали", &report.top_parts),
+        ("Операторы", &report.top_operators),
+    ] {
+        writeln!(text, "\n{title} с наибольшим превышением:")?;
+        for (i, entry) in top.iter().enumerate() {
+            writeln!(
+                text,
+                "{}. {}: {} мин., наладок {}",
+                i + 1,
+                entry.name,
+                entry.total_overrun,
+                entry.long_setups
+            )?;
+        }
+    }
+    Ok(text)
+}
+
+pub fn trend_subject(period: &DateRange) -> String {
+    format!("Сводка по длительным наладкам за {}", period)
+}
+
+/// Отправляет сводку `name` за `period` через очередь писем и записывает итог в журнал.
+pub async fn send_trend_with_retry(
+    services: &Services,
+    settings: &Settings,
+    name: &str,
+    period: &DateRange,
+    recipients: &[String],
+) -> Result<DeliveryStatus> {
+    let settings = &norms::with_norms(services, settings).await;
+    let subject = trend_subject(period);
+    let previous_period = period.previous();
+    let data = retry(&settings.general.retry, || async {
+        let mut source = services.source.lock().await;
+        source.reconnect(settings).await?;
+        let current = source.fetch_merged_setups(settings, period).await?;
+        let previous = source
+            .fetch_merged_setups(settings, &previous_period)
+            .await?;
+        Ok((current, previous))
+    })
+    .await;
+    let (current, previous) = match data {
+        Ok(data) => data,
+        Err(e) => {
+            record_delivery(
+                services,
+                name,
+                period,
+                &subject,
+                recipients,
+                &[],
+                DeliveryStatus::Failed,
+                Some(format!("{e:#}")),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+    if current.is_empty() && previous.is_empty() {
+        info!(
+            "Наладок за {} и {} не было, отправлять нечего.",
+            period, previous_period
+        );
+        record_delivery(
+            services,
+            name,
+            period,
+            &subject,
+            recipients,
+            &[],
+            DeliveryStatus::Empty,
+            None,
+        )
+        .await;
+        return Ok(DeliveryStatus::Empty);
+    }
+
+    let report = build_trend(&current, &previous, period, settings);
+    let message = services.mailer.lock().await.compose(
+        &subject,
+        &generate_trend_html(&report)?,
+        &generate_trend_text(&report)?,
+        &[],
+        "Уведомлятель",
+        recipients,
+    )?;
+    // Сами наладки в журнал не пишутся: длительные наладки уже есть в ежедневных отчетах
+    let delivery_id = record_delivery(
+        services,
+        name,
+        period,
+        &subject,
+        recipients,
+        &[],
+        DeliveryStatus::Queued,
+        None,
+    )
+    .await;
+    outbox::send(
+        services,
+        settings,
+        &subject,
+        &message,
+        recipients,
+        delivery_id,
+    )
+    .await
+}
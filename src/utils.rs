@@ -1,17 +1,189 @@
-use chrono::Local;
 use eyre::Result;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::time::Instant;
 use tokio::time::sleep;
 use tokio::time::Duration as TokioDuration;
 use tracing::{info, warn};
 
-pub const MAX_RETRY_ATTEMPTS: usize = 3;
-pub const RETRY_DELAY: u64 = 5;
+/// Параметры повторных попыток из `[general.retry]`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub initial_delay: u64, // Seconds
+    pub max_delay: u64,     // Seconds
+    /// Во сколько раз растет пауза после каждой неудачной попытки
+    pub multiplier: f64,
+    /// Случайное отклонение паузы в долях от нее, от 0 до 1
+    pub jitter: f64,
+    /// Общее время на все попытки в секундах, 0 - без ограничения
+    pub deadline: u64,
+}
+
+/// Можно ли исправить ошибку повторной попыткой.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Сбой сети, перезапуск сервера, таймаут
+    Transient,
+    /// Неверный пароль, ошибка в запросе, неверный адрес: повтор не поможет
+    Permanent,
+}
+
+/// Ошибка в настройках или данных, которую не исправить повторной попыткой.
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl fmt::Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-pub async fn retry<F, Fut, T>(max_attempts: usize, retry_delay: u64, mut f: F) -> Result<T>
+impl std::error::Error for PermanentError {}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: 5,
+            max_delay: 300,
+            multiplier: 2.0,
+            jitter: 0.2,
+            deadline: 900,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_attempts == 0 {
+            eyre::bail!("general.retry.max_attempts должен быть положительным");
+        }
+        if self.multiplier < 1.0 {
+            eyre::bail!("general.retry.multiplier должен быть не меньше 1");
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            eyre::bail!("general.retry.jitter должен быть от 0 до 1");
+        }
+        Ok(())
+    }
+
+    /// Пауза после неудачной попытки `attempt` (с единицы) без случайного отклонения.
+    pub fn delay(&self, attempt: usize) -> TokioDuration {
+        let exponent = attempt.saturating_sub(1).min(64) as i32;
+        let secs =
+            (self.initial_delay as f64 * self.multiplier.powi(exponent)).min(self.max_delay as f64);
+        TokioDuration::from_secs_f64(secs)
+    }
+
+    /// Пауза со случайным отклонением в пределах `jitter`, чтобы несколько
+    /// клиентов не переподключались к серверу одновременно.
+    pub fn jittered_delay(&self, attempt: usize) -> TokioDuration {
+        let factor = 1.0 + self.jitter * (fastrand::f64() * 2.0 - 1.0);
+        self.delay(attempt).mul_f64(factor)
+    }
+}
+
+/// Ошибки авторизации, синтаксиса запроса, проверки сертификата и неверные адреса
+/// постоянные, сетевые сбои и все неизвестные ошибки считаются временными.
+pub fn classify(error: &eyre::Report) -> ErrorClass {
+    for cause in error.chain() {
+        if cause.is::<PermanentError>() {
+            return ErrorClass::Permanent;
+        }
+        if let Some(e) = cause.downcast_ref::<async_native_tls::Error>() {
+            if is_certificate_error(&e.to_string()) {
+                return ErrorClass::Permanent;
+            }
+            // Обрыв соединения во время рукопожатия и прочие сбои TLS
+            continue;
+        }
+        if let Some(e) = cause.downcast_ref::<tiberius::error::Error>() {
+            return classify_db(e);
+        }
+        if let Some(e) = cause.downcast_ref::<async_smtp::error::Error>() {
+            return classify_smtp(e);
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return classify_io(e.kind());
+        }
+    }
+    ErrorClass::Transient
+}
+
+fn classify_db(error: &tiberius::error::Error) -> ErrorClass {
+    use tiberius::error::Error;
+    match error {
+        Error::Io { kind, .. } => classify_io(*kind),
+        Error::Server(token) => match token.code() {
+            // Ошибка входа, нет доступа к базе, нет прав
+            18456 | 18470 | 18486 | 4060 | 229 | 230 => ErrorClass::Permanent,
+            // Синтаксис, неизвестный столбец или таблица, неверный тип
+            102 | 105 | 156 | 207 | 208 | 245 | 8114 => ErrorClass::Permanent,
+            _ => ErrorClass::Transient,
+        },
+        Error::Tls(message) if is_certificate_error(message) => ErrorClass::Permanent,
+        Error::Conversion(_) | Error::Utf8 | Error::Utf16 | Error::ParseInt(_) => {
+            ErrorClass::Permanent
+        }
+        _ => ErrorClass::Transient,
+    }
+}
+
+fn classify_smtp(error: &async_smtp::error::Error) -> ErrorClass {
+    use async_smtp::error::Error;
+    match error {
+        Error::Permanent(_) | Error::AddrParseError(_) | Error::Client(_) => ErrorClass::Permanent,
+        Error::Io(e) => classify_io(e.kind()),
+        _ => ErrorClass::Transient,
+    }
+}
+
+/// Сертификат сервера не прошел проверку: недоверенный, просроченный или выдан на другое имя.
+/// Windows (Schannel) сообщает об этом кодом ошибки, текст которого зависит от языка системы,
+/// OpenSSL - текстом результата проверки.
+fn is_certificate_error(message: &str) -> bool {
+    // SEC_E_WRONG_PRINCIPAL, SEC_E_UNTRUSTED_ROOT, SEC_E_CERT_UNKNOWN, SEC_E_CERT_EXPIRED,
+    // CERT_E_EXPIRED, CERT_E_UNTRUSTEDROOT, CERT_E_CN_NO_MATCH
+    const SCHANNEL_CERTIFICATE_ERRORS: [u32; 7] = [
+        0x8009_0322,
+        0x8009_0325,
+        0x8009_0327,
+        0x8009_0328,
+        0x800B_0101,
+        0x800B_0109,
+        0x800B_010F,
+    ];
+    let os_error = message
+        .rsplit_once("(os error ")
+        .and_then(|(_, code)| code.strip_suffix(')'))
+        .and_then(|code| code.parse::<i32>().ok());
+    if let Some(code) = os_error {
+        return SCHANNEL_CERTIFICATE_ERRORS.contains(&(code as u32));
+    }
+    let message = message.to_lowercase();
+    message.contains("certificate") || message.contains("verify failed")
+}
+
+fn classify_io(kind: io::ErrorKind) -> ErrorClass {
+    match kind {
+        io::ErrorKind::InvalidInput | io::ErrorKind::PermissionDenied => ErrorClass::Permanent,
+        _ => ErrorClass::Transient,
+    }
+}
+
+/// Повторяет `f` по правилам `policy`. Постоянные ошибки (см. [`classify`])
+/// возвращаются сразу, без повторов.
+pub async fn retry<F, Fut, T>(policy: &RetryPolicy, mut f: F) -> Result<T>
 where
     F: FnMut() -> Fut,
     Fut: std::future::Future<Output = Result<T>>,
 {
+    let started = Instant::now();
+    let deadline = (policy.deadline > 0).then(|| TokioDuration::from_secs(policy.deadline));
+    let max_attempts = policy.max_attempts.max(1);
     for attempt in 1..=max_attempts {
         match f().await {
             Ok(result) => return Ok(result),
@@ -20,12 +192,23 @@ where
                     "Попытка {} из {} не удалась. Ошибка: {:?}",
                     attempt, max_attempts, e
                 );
-                if attempt < max_attempts {
-                    info!("Повторная попытка через {} секунд...", retry_delay);
-                    sleep(TokioDuration::from_secs(retry_delay)).await;
-                } else {
+                if classify(&e) == ErrorClass::Permanent {
+                    warn!("Ошибка не исправится повторной попыткой, попытки прекращены");
                     return Err(e);
                 }
+                if attempt == max_attempts {
+                    return Err(e);
+                }
+                let delay = policy.jittered_delay(attempt);
+                if deadline.is_some_and(|deadline| started.elapsed() + delay > deadline) {
+                    warn!("Время на попытки ({} сек.) исчерпано", policy.deadline);
+                    return Err(e);
+                }
+                info!(
+                    "Повторная попытка через {:.0} секунд...",
+                    delay.as_secs_f64()
+                );
+                sleep(delay).await;
             }
         }
     }
@@ -41,20 +224,3 @@ pub fn parse_time(time_str: &str) -> Result<(u32, u32), eyre::Error> {
     let minute: u32 = parts[1].parse()?;
     Ok((hour, minute))
 }
-
-pub fn next_send_time(
-    now: chrono::DateTime<Local>,
-    send_time: (u32, u32),
-) -> chrono::DateTime<Local> {
-    let mut next = now
-        .date_naive()
-        .and_hms_opt(send_time.0, send_time.1, 0)
-        .expect("Неверное время")
-        .and_local_timezone(now.timezone())
-        .unwrap();
-    if next <= now {
-        next += chrono::Duration::days(1);
-    }
-    next
-}
-
@@ -0,0 +1,150 @@
+use crate::config::Settings;
+use crate::history::{DeliveryStatus, History};
+use crate::init::Services;
+use crate::models::{PartData, ReportRow, SetupKey};
+use crate::norms::with_norms;
+use crate::outbox;
+use crate::reports::{generate_alert_html, generate_alert_text};
+use chrono::{Duration, Local, NaiveDateTime};
+use eyre::Result;
+use std::collections::HashSet;
+use tokio::time::{sleep, Duration as TokioDuration};
+use tracing::{debug, error, info, warn};
+
+/// Помнит, о каких наладках уже отправлено оповещение, чтобы не повторять его.
+/// Отметки хранятся в журнале отправок и переживают перезапуск.
+#[derive(Debug, Default)]
+pub struct AlertTracker {
+    alerted: HashSet<SetupKey>,
+}
+
+impl AlertTracker {
+    /// Отметки об оповещениях по наладкам, начатым не раньше `since`.
+    pub fn load(history: &History, since: NaiveDateTime) -> Result<Self> {
+        Ok(Self {
+            alerted: history.alerted_setups(since)?.into_iter().collect(),
+        })
+    }
+
+    /// Наладки из `running`, превысившие лимит и еще не оповещенные.
+    pub fn due<'a>(&self, running: &'a [PartData], settings: &Settings) -> Vec<&'a PartData> {
+        running
+            .iter()
+            .filter(|part| !self.alerted.contains(&SetupKey::from(*part)))
+            .filter(|part| {
+                part.setup_minutes(settings)
+                    > settings.get_setup_limit(&part.machine, &part.part_name, part.setup)
+            })
+            .collect()
+    }
+
+    /// Забывает наладки, начатые раньше `since`: они уже не попадают в выборку.
+    /// Отметки остальных наладок хранятся, даже если их нет в текущей выборке.
+    pub fn forget_before(&mut self, since: NaiveDateTime) {
+        self.alerted.retain(|key| key.start_setup_time >= since);
+    }
+
+    pub fn mark_alerted(&mut self, part: &PartData) {
+        self.alerted.insert(SetupKey::from(part));
+    }
+}
+
+/// Отслеживание для режима работы по расписанию: при выключенных `alerts` никогда не завершается.
+pub async fn watch_if_enabled(services: Services, settings: Settings) {
+    if settings.alerts.enabled {
+        watch(services, settings).await
+    } else {
+        std::future::pending().await
+    }
+}
+
+/// Опрашивает базу каждые `alerts.poll_interval` минут и оповещает о каждой
+/// незавершенной наладке, как только она превышает свой лимит.
+pub async fn watch(services: Services, mut settings: Settings) {
+    let since = Local::now().naive_local() - Duration::hours(settings.alerts.lookback_hours);
+    let mut tracker = match AlertTracker::load(&*services.history.lock().await, since) {
+        Ok(tracker) => tracker,
+        Err(e) => {
+            warn!("Не удалось прочитать отправленные оповещения: {:?}", e);
+            AlertTracker::default()
+        }
+    };
+    info!(
+        "Отслеживание текущих наладок: опрос каждые {} мин.",
+        settings.alerts.poll_interval
+    );
+    loop {
+        if let Err(e) = settings.update() {
+            warn!(
+                "Не удалось обновить параметры приложения: {}\nИспользуются предыдущие настройки.",
+                e
+            );
+        }
+        let settings_with_norms = with_norms(&services, &settings).await;
+        if let Err(e) = poll(&services, &settings_with_norms, &mut tracker).await {
+            error!("Ошибка проверки текущих наладок: {:?}", e);
+        }
+        sleep(TokioDuration::from_secs(settings.alerts.poll_interval * 60)).await;
+    }
+}
+
+async fn poll(services: &Services, settings: &Settings, tracker: &mut AlertTracker) -> Result<()> {
+    let now = Local::now().naive_local();
+    let since = now - Duration::hours(settings.alerts.lookback_hours);
+    let running = {
+        let mut source = services.source.lock().await;
+        source.reconnect(settings).await?;
+        source.fetch_running_setups(since, now).await?
+    };
+    debug!("Незавершенных наладок: {}", running.len());
+    tracker.forget_before(since);
+
+    for part in tracker.due(&running, settings) {
+        let row = ReportRow::new(part, settings);
+        match send_alert(services, settings, &row).await {
+            Ok(status) => {
+                info!(
+                    "Оповещение {}: {}, {} ({} мин. при лимите {})",
+                    if status == DeliveryStatus::Queued {
+                        "поставлено в очередь"
+                    } else {
+                        "отправлено"
+                    },
+                    row.machine,
+                    row.part_name,
+                    row.setup_minutes,
+                    row.limit
+                );
+                tracker.mark_alerted(part);
+                if let Err(e) = services
+                    .history
+                    .lock()
+                    .await
+                    .record_alert(&SetupKey::from(part))
+                {
+                    warn!("Не удалось записать оповещение в журнал: {:?}", e);
+                }
+            }
+            Err(e) => error!("Не удалось отправить оповещение: {:?}", e),
+        }
+    }
+    Ok(())
+}
+
+async fn send_alert(
+    services: &Services,
+    settings: &Settings,
+    row: &ReportRow,
+) -> Result<DeliveryStatus> {
+    let subject = format!("Длительная наладка: {}, {}", row.machine, row.part_name);
+    let recipients = settings.alerts.recipients(&settings.smtp);
+    let message = services.mailer.lock().await.compose(
+        &subject,
+        &generate_alert_html(row)?,
+        &generate_alert_text(row),
+        &[],
+        "Уведомлятель",
+        recipients,
+    )?;
+    outbox::send(services, settings, &subject, &message, recipients, None).await
+}